@@ -0,0 +1,203 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+const DECIMALS: u32 = 4;
+const SCALE: i64 = 10i64.pow(DECIMALS);
+
+/// Why an amount can't be parsed or computed.
+#[derive(Debug, Error, PartialEq, Eq, Clone)]
+pub enum AmountError {
+    /// The result doesn't fit into an amount.
+    #[error("amount overflow")]
+    Overflow,
+    /// The amount has more than four decimal places.
+    #[error("amount '{0}' has more than {DECIMALS} decimal places")]
+    Precision(String),
+    /// The text isn't a decimal number.
+    #[error("'{0}' is not a valid amount")]
+    Malformed(String),
+}
+
+/// Fixed-point decimal with four decimal places, stored as an integer number of
+/// ten-thousandths so that sums of amounts are exact.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i64);
+
+impl Amount {
+    /// Nothing, the balance of a new client.
+    pub const ZERO: Amount = Amount(0);
+
+    /// Sum of both amounts, an error instead of wrapping around.
+    pub fn checked_add(self, other: Amount) -> Result<Amount, AmountError> {
+        self.0
+            .checked_add(other.0)
+            .map(Amount)
+            .ok_or(AmountError::Overflow)
+    }
+
+    /// Difference of both amounts, an error instead of wrapping around.
+    pub fn checked_sub(self, other: Amount) -> Result<Amount, AmountError> {
+        self.0
+            .checked_sub(other.0)
+            .map(Amount)
+            .ok_or(AmountError::Overflow)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = AmountError;
+
+    /// Parses a plain decimal like `-12.3456`. Digits beyond the fourth decimal
+    /// place are only accepted if they are zeros, nothing is ever rounded.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || AmountError::Malformed(s.to_owned());
+        let (negative, unsigned) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        if integer.is_empty() && fraction.is_empty() {
+            return Err(malformed());
+        }
+        if !integer
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+        {
+            return Err(malformed());
+        }
+
+        let (fraction, rest) = fraction.split_at(fraction.len().min(DECIMALS as usize));
+        if rest.bytes().any(|b| b != b'0') {
+            return Err(AmountError::Precision(s.to_owned()));
+        }
+
+        let mut value: i64 = 0;
+        for digit in integer.bytes().chain(fraction.bytes()) {
+            value = value
+                .checked_mul(10)
+                .and_then(|v| v.checked_add(i64::from(digit - b'0')))
+                .ok_or(AmountError::Overflow)?;
+        }
+        value = value
+            .checked_mul(10i64.pow(DECIMALS - fraction.len() as u32))
+            .ok_or(AmountError::Overflow)?;
+
+        Ok(Amount(if negative { -value } else { value }))
+    }
+}
+
+/// Always prints all four decimal places, e.g. `1.5000`.
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let value = self.0.unsigned_abs();
+        let integer = value / SCALE as u64;
+        let fraction = value % SCALE as u64;
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            integer,
+            fraction,
+            width = DECIMALS as usize
+        )
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct AmountVisitor;
+
+        impl<'de> de::Visitor<'de> for AmountVisitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(
+                    f,
+                    "a decimal amount with at most {} decimal places",
+                    DECIMALS
+                )
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Amount, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(AmountVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("1".parse(), Ok(Amount(10000)));
+        assert_eq!("1.5".parse(), Ok(Amount(15000)));
+        assert_eq!("0.0001".parse(), Ok(Amount(1)));
+        assert_eq!(".25".parse(), Ok(Amount(2500)));
+        assert_eq!("-2.75".parse(), Ok(Amount(-27500)));
+        assert_eq!("3.140000".parse(), Ok(Amount(31400)));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            "1.00001".parse::<Amount>(),
+            Err(AmountError::Precision("1.00001".to_owned()))
+        );
+        assert_eq!(
+            "99999999999999999999".parse::<Amount>(),
+            Err(AmountError::Overflow)
+        );
+        for input in ["", ".", "-", "1.2.3", "1e5", "NaN", "inf", " 1"] {
+            assert_eq!(
+                input.parse::<Amount>(),
+                Err(AmountError::Malformed(input.to_owned()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Amount(10000).to_string(), "1.0000");
+        assert_eq!(Amount(15000).to_string(), "1.5000");
+        assert_eq!(Amount(1).to_string(), "0.0001");
+        assert_eq!(Amount(-5000).to_string(), "-0.5000");
+        assert_eq!(Amount::ZERO.to_string(), "0.0000");
+    }
+
+    #[test]
+    fn test_exact_sum() {
+        let cent: Amount = "0.01".parse().unwrap();
+        let mut sum = Amount::ZERO;
+        for _ in 0..100000 {
+            sum = sum.checked_add(cent).unwrap();
+        }
+        assert_eq!(sum, "1000".parse().unwrap());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let max = Amount(i64::MAX);
+        assert_eq!(max.checked_add(Amount(1)), Err(AmountError::Overflow));
+        assert_eq!(
+            Amount(i64::MIN).checked_sub(Amount(1)),
+            Err(AmountError::Overflow)
+        );
+    }
+}
@@ -0,0 +1,488 @@
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    future::Future,
+    sync::{Arc, OnceLock},
+};
+use tokio::{
+    net::TcpListener,
+    sync::{Mutex, MutexGuard, Notify},
+};
+
+use crate::output::{ClientRecord, RejectionRecord, TransactionRecord};
+use crate::transaction::Transaction;
+use crate::transaction_engine::{ApplyError, ClientOrder, TransactionEngine, TransactionError};
+
+type SharedEngine = Arc<Shared>;
+
+struct Shared {
+    engine: Mutex<TransactionEngine>,
+    /// The first failure of the engine, which may be half applied from then on.
+    /// All further requests are refused and the server shuts down.
+    failure: OnceLock<anyhow::Error>,
+    failed: Notify,
+}
+
+impl Shared {
+    fn new(engine: TransactionEngine) -> Shared {
+        Shared {
+            engine: Mutex::new(engine),
+            failure: OnceLock::new(),
+            failed: Notify::new(),
+        }
+    }
+
+    async fn engine(&self) -> Result<MutexGuard<'_, TransactionEngine>, ApiError> {
+        let engine = self.engine.lock().await;
+        match self.failure.get() {
+            Some(_) => Err(ApiError::Poisoned),
+            None => Ok(engine),
+        }
+    }
+
+    fn fail(&self, error: anyhow::Error) -> ApiError {
+        let message = error.to_string();
+        let _ = self.failure.set(error);
+        self.failed.notify_one();
+        ApiError::Internal(message)
+    }
+}
+
+/// Body of a POST to `/transactions`.
+#[derive(Debug)]
+enum Submission {
+    Single(Transaction),
+    Batch(Vec<Transaction>),
+}
+
+impl Submission {
+    /// A transaction or an array of them, amounts may be strings or numbers.
+    fn from_json(value: Value) -> Result<Submission, ApiError> {
+        match value {
+            Value::Array(values) => values
+                .into_iter()
+                .map(Transaction::from_json)
+                .collect::<Result<_, _>>()
+                .map(Submission::Batch),
+            value => Transaction::from_json(value).map(Submission::Single),
+        }
+        .map_err(|e| ApiError::Malformed(StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))
+    }
+}
+
+/// Outcome of a submitted transaction, the reason is the code of the rejection.
+/// `code` is the http status the transaction gets on its own.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+struct Outcome {
+    tx: u32,
+    status: &'static str,
+    code: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl Outcome {
+    fn new(tx: u32, error: Option<&TransactionError>) -> Outcome {
+        Outcome {
+            tx,
+            status: if error.is_some() { "rejected" } else { "ok" },
+            code: error.map_or(StatusCode::OK, status_of).as_u16(),
+            reason: error.map(TransactionError::code),
+            message: error.map(TransactionError::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    reason: &'static str,
+    message: String,
+}
+
+/// Errors of the engine as responses, rejections keep their reason code.
+enum ApiError {
+    Rejected(TransactionError),
+    Internal(String),
+    /// An earlier request failed and the server is shutting down.
+    Poisoned,
+    /// The body isn't json or not a transaction.
+    Malformed(StatusCode, String),
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> ApiError {
+        ApiError::Malformed(rejection.status(), rejection.body_text())
+    }
+}
+
+fn status_of(error: &TransactionError) -> StatusCode {
+    match error {
+        TransactionError::UnknownClient(_) | TransactionError::TxNotFound(_) => {
+            StatusCode::NOT_FOUND
+        }
+        TransactionError::WrongClient(..) => StatusCode::FORBIDDEN,
+        TransactionError::AccountLocked(_) => StatusCode::LOCKED,
+        TransactionError::DuplicateTx(_)
+        | TransactionError::TxRejected(_)
+        | TransactionError::AlreadyDisputed(_)
+        | TransactionError::NotDisputed(_)
+        | TransactionError::DisputeSettled(..)
+        | TransactionError::NotDisputable(..) => StatusCode::CONFLICT,
+        TransactionError::InsufficientFunds(_)
+        | TransactionError::Amount(_)
+        | TransactionError::Books(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        TransactionError::Invalid(_) => StatusCode::BAD_REQUEST,
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, body) = match self {
+            ApiError::Rejected(error) => (
+                status_of(&error),
+                ErrorBody {
+                    reason: error.code(),
+                    message: error.to_string(),
+                },
+            ),
+            ApiError::Internal(message) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorBody {
+                    reason: "internal",
+                    message,
+                },
+            ),
+            ApiError::Poisoned => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorBody {
+                    reason: "unavailable",
+                    message: "the engine failed, the server is shutting down".to_owned(),
+                },
+            ),
+            ApiError::Malformed(status, message) => (
+                status,
+                ErrorBody {
+                    reason: "malformed_request",
+                    message,
+                },
+            ),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+/// A single transaction is answered with the status code of its rejection. A batch
+/// is answered with the outcome of every transaction in order, with 200 if all of
+/// them were applied, 422 if none was and 207 otherwise. A failure of the engine
+/// stops the server.
+async fn post_transactions(
+    State(shared): State<SharedEngine>,
+    body: Result<Json<Value>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(body) = body?;
+    let submission = Submission::from_json(body)?;
+    let mut engine = shared.engine().await?;
+    let response = match submission {
+        Submission::Single(transaction) => match engine.apply(&transaction) {
+            Ok(()) => Json(Outcome::new(transaction.tx, None)).into_response(),
+            Err(ApplyError::Rejected(error)) => {
+                let outcome = Outcome::new(transaction.tx, Some(&error));
+                (status_of(&error), Json(outcome)).into_response()
+            }
+            Err(ApplyError::Failed(e)) => return Err(shared.fail(e)),
+        },
+        Submission::Batch(transactions) => {
+            let mut outcomes = Vec::with_capacity(transactions.len());
+            for transaction in &transactions {
+                let outcome = match engine.apply(transaction) {
+                    Ok(()) => Outcome::new(transaction.tx, None),
+                    Err(ApplyError::Rejected(error)) => Outcome::new(transaction.tx, Some(&error)),
+                    Err(ApplyError::Failed(e)) => return Err(shared.fail(e)),
+                };
+                outcomes.push(outcome);
+            }
+            let rejected = outcomes.iter().filter(|o| o.reason.is_some()).count();
+            let status = match rejected {
+                0 => StatusCode::OK,
+                n if n == outcomes.len() => StatusCode::UNPROCESSABLE_ENTITY,
+                _ => StatusCode::MULTI_STATUS,
+            };
+            (status, Json(outcomes)).into_response()
+        }
+    };
+    engine.sync().map_err(|e| shared.fail(e))?;
+    Ok(response)
+}
+
+async fn get_clients(
+    State(shared): State<SharedEngine>,
+) -> Result<Json<Vec<ClientRecord>>, ApiError> {
+    let engine = shared.engine().await?;
+    let clients = engine
+        .sorted_clients(ClientOrder::Id)
+        .into_iter()
+        .map(|(id, client)| ClientRecord::new(id, client))
+        .collect();
+    Ok(Json(clients))
+}
+
+async fn get_client(
+    State(shared): State<SharedEngine>,
+    Path(id): Path<u16>,
+) -> Result<Json<ClientRecord>, ApiError> {
+    let engine = shared.engine().await?;
+    match engine.client(id) {
+        Some(client) => Ok(Json(ClientRecord::new(id, client))),
+        None => Err(ApiError::Rejected(TransactionError::UnknownClient(id))),
+    }
+}
+
+async fn get_transaction(
+    State(shared): State<SharedEngine>,
+    Path(tx): Path<u32>,
+) -> Result<Json<TransactionRecord>, ApiError> {
+    let engine = shared.engine().await?;
+    match engine.transaction(tx) {
+        Some(stored) => Ok(Json(TransactionRecord::new(tx, stored))),
+        None => Err(ApiError::Rejected(TransactionError::TxNotFound(tx))),
+    }
+}
+
+async fn get_rejections(
+    State(shared): State<SharedEngine>,
+) -> Result<Json<Vec<RejectionRecord>>, ApiError> {
+    let engine = shared.engine().await?;
+    Ok(Json(
+        engine.rejected().iter().map(RejectionRecord::new).collect(),
+    ))
+}
+
+fn router(engine: SharedEngine) -> Router {
+    Router::new()
+        .route("/transactions", post(post_transactions))
+        .route("/transactions/{tx}", get(get_transaction))
+        .route("/clients", get(get_clients))
+        .route("/clients/{id}", get(get_client))
+        .route("/rejections", get(get_rejections))
+        .with_state(engine)
+}
+
+/// Serves the HTTP API until `shutdown` completes, then returns the engine. A
+/// failure of the books or the write-ahead log stops the server and is returned.
+pub async fn serve(
+    listener: TcpListener,
+    engine: TransactionEngine,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<TransactionEngine> {
+    let shared = Arc::new(Shared::new(engine));
+    let failed = shared.clone();
+    axum::serve(listener, router(shared.clone()))
+        .with_graceful_shutdown(async move {
+            tokio::select! {
+                _ = shutdown => (),
+                _ = failed.failed.notified() => (),
+            }
+        })
+        .await?;
+    let shared = Arc::try_unwrap(shared).map_err(|_| anyhow!("Requests are still running"))?;
+    match shared.failure.into_inner() {
+        Some(error) => Err(error),
+        None => Ok(shared.engine.into_inner()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    async fn call(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        let body = match body {
+            Some(body) => Body::from(body.to_string()),
+            None => Body::empty(),
+        };
+        let response = app
+            .clone()
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    fn app() -> Router {
+        router(Arc::new(Shared::new(TransactionEngine::new().unwrap())))
+    }
+
+    #[tokio::test]
+    async fn test_post_transactions() {
+        let app = app();
+        let deposit = json!({"type": "deposit", "client": 1, "tx": 1, "amount": "5"});
+        let (status, body) = call(&app, "POST", "/transactions", Some(deposit.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"tx": 1, "status": "ok", "code": 200}));
+
+        let (status, body) = call(&app, "POST", "/transactions", Some(deposit)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["reason"], "duplicate_tx");
+
+        let batch = json!([
+            {"type": "withdrawal", "client": 1, "tx": 2, "amount": "9"},
+            {"type": "dispute", "client": 1, "tx": 1},
+            {"type": "chargeback", "client": 1, "tx": 1},
+            {"type": "deposit", "client": 1, "tx": 3, "amount": "1"},
+        ]);
+        let (status, body) = call(&app, "POST", "/transactions", Some(batch)).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        let statuses: Vec<(&str, u64, &str)> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|o| {
+                (
+                    o["status"].as_str().unwrap(),
+                    o["code"].as_u64().unwrap(),
+                    o["reason"].as_str().unwrap_or(""),
+                )
+            })
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("rejected", 422, "insufficient_funds"),
+                ("ok", 200, ""),
+                ("ok", 200, ""),
+                ("rejected", 423, "account_locked")
+            ]
+        );
+        let batch = json!([
+            {"type": "deposit", "client": 1, "tx": 3, "amount": "1"},
+            {"type": "dispute", "client": 1, "tx": 9},
+        ]);
+        let (status, body) = call(&app, "POST", "/transactions", Some(batch)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body[0]["code"], 409);
+        assert_eq!(body[1]["code"], 404);
+        let (status, body) = call(&app, "POST", "/transactions", Some(json!([]))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!([]));
+
+        let dispute = json!({"type": "dispute", "client": 2, "tx": 1});
+        let (status, _) = call(&app, "POST", "/transactions", Some(dispute)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let withdrawal = json!({"type": "withdrawal", "client": 1, "tx": 4, "amount": "1"});
+        let (status, _) = call(&app, "POST", "/transactions", Some(withdrawal)).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        let invalid = json!({"type": "deposit", "client": 1, "tx": 5});
+        let (status, body) = call(&app, "POST", "/transactions", Some(invalid)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["reason"], "missing_amount");
+        let (status, body) = call(&app, "POST", "/transactions", Some(json!({"type": "x"}))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["reason"], "malformed_request");
+        let numeric = json!({"type": "deposit", "client": 2, "tx": 6, "amount": 2.5});
+        let (status, body) = call(&app, "POST", "/transactions", Some(numeric)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"tx": 6, "status": "ok", "code": 200}));
+        let (_, body) = call(&app, "GET", "/clients/2", None).await;
+        assert_eq!(body["available"], "2.5000");
+        let request = Request::builder()
+            .method("POST")
+            .uri("/transactions")
+            .header("content-type", "application/json")
+            .body(Body::from("{\"type\":"))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["reason"], "malformed_request");
+
+        let (status, body) = call(&app, "GET", "/rejections", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 8);
+        assert_eq!(body[7]["reason"], "missing_amount");
+    }
+
+    #[tokio::test]
+    async fn test_queries() {
+        let app = app();
+        let batch = json!([
+            {"type": "deposit", "client": 2, "tx": 1, "amount": "1.5"},
+            {"type": "deposit", "client": 1, "tx": 2, "amount": "3"},
+            {"type": "dispute", "client": 1, "tx": 2},
+        ]);
+        call(&app, "POST", "/transactions", Some(batch)).await;
+
+        let (status, body) = call(&app, "GET", "/clients", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["client"], 1);
+        assert_eq!(body[1]["client"], 2);
+        let (status, body) = call(&app, "GET", "/clients/1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"client": 1, "available": "0.0000", "held": "3.0000", "total": "3.0000", "locked": false})
+        );
+        let (status, body) = call(&app, "GET", "/clients/7", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["reason"], "unknown_client");
+
+        let (status, body) = call(&app, "GET", "/transactions/2", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"tx": 2, "client": 1, "type": "deposit", "amount": "3.0000", "state": "disputed"})
+        );
+        let (status, body) = call(&app, "GET", "/transactions/9", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["reason"], "tx_not_found");
+        let (status, _) = call(&app, "GET", "/clients/abc", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_engine_failure() {
+        let shared = Arc::new(Shared::new(TransactionEngine::new().unwrap()));
+        let app = router(shared.clone());
+        let deposit = json!({"type": "deposit", "client": 1, "tx": 1, "amount": "5"});
+        let (status, _) = call(&app, "POST", "/transactions", Some(deposit.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let response = shared.fail(anyhow!("disk full")).into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        // the shutdown of the server is triggered
+        shared.failed.notified().await;
+        let (status, body) = call(&app, "POST", "/transactions", Some(deposit)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["reason"], "unavailable");
+        let (status, _) = call(&app, "GET", "/clients/1", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,503 @@
+use serde::{Serialize, Serializer};
+use std::{collections::HashMap, fmt};
+use thiserror::Error;
+
+use crate::amount::{Amount, AmountError};
+use crate::output::Record;
+use crate::transaction::{Transaction, TransactionType};
+use crate::transaction_engine::{amount_of, Client, StoredTransaction, TransactionError};
+
+/// Why the books refused a posting or don't agree with the engine.
+#[derive(Debug, Error, PartialEq, Eq, Clone)]
+pub enum BookkeepingError {
+    /// The debits and credits of a posting differ.
+    #[error("posting {0} doesn't balance, debits are {1} and credits are {2}")]
+    Unbalanced(u64, Amount, Amount),
+    /// A transaction would leave the held account of a client negative.
+    #[error("transaction {0} leaves the held account of client {1} at {2}")]
+    NegativeHeld(u32, u16, Amount),
+    /// The client accounts don't match the balances of the engine.
+    #[error("books of client {1} don't match the engine after transaction {0}")]
+    Mismatch(u32, u16),
+    /// A balance doesn't fit into an amount.
+    #[error(transparent)]
+    Amount(#[from] AmountError),
+}
+
+impl BookkeepingError {
+    /// Reason code of a transaction the books refused.
+    pub fn code(&self) -> &'static str {
+        match self {
+            BookkeepingError::Unbalanced(..) => "unbalanced",
+            BookkeepingError::NegativeHeld(..) => "negative_held",
+            BookkeepingError::Mismatch(..) => "books_mismatch",
+            BookkeepingError::Amount(_) => "amount_overflow",
+        }
+    }
+}
+
+/// Client accounts are liabilities of the house, house cash is an asset and
+/// chargeback loss an expense.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Account {
+    /// Available funds of a client, a liability.
+    ClientAvailable(u16),
+    /// Held funds of a client, a liability.
+    ClientHeld(u16),
+    /// Cash of the house, an asset.
+    HouseCash,
+    /// Withdrawals paid out again by chargebacks, an expense.
+    ChargebackLoss,
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Account::ClientAvailable(client) => write!(f, "client_available:{}", client),
+            Account::ClientHeld(client) => write!(f, "client_held:{}", client),
+            Account::HouseCash => write!(f, "house_cash"),
+            Account::ChargebackLoss => write!(f, "chargeback_loss"),
+        }
+    }
+}
+
+impl Serialize for Account {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// One line of the journal, every posting consists of at least one debit and one
+/// credit line. The opening posting of restored clients has no transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JournalEntry {
+    /// Number of the posting the line belongs to.
+    pub posting: u64,
+    /// Transaction that caused the posting, none for the opening one.
+    pub tx: Option<u32>,
+    /// Account the line is booked on.
+    pub account: Account,
+    /// Amount debited, if the line is a debit.
+    pub debit: Option<Amount>,
+    /// Amount credited, if the line is a credit.
+    pub credit: Option<Amount>,
+}
+
+impl Record for JournalEntry {
+    const COLUMNS: &'static [&'static str] = &["posting", "tx", "account", "debit", "credit"];
+}
+
+/// Double-entry books kept next to the engine. Each applied transaction is posted
+/// as balanced debits and credits, afterwards the books have to agree with the
+/// balances of the client in the engine.
+#[derive(Debug, Default)]
+pub struct Books {
+    /// Debits minus credits per account.
+    balances: HashMap<Account, Amount>,
+    debits: Amount,
+    credits: Amount,
+    journal: Vec<JournalEntry>,
+    postings: u64,
+}
+
+impl Books {
+    /// Opens the books with the current balances of `clients`, backed by house cash.
+    pub fn new(clients: &HashMap<u16, Client>) -> Result<Books, BookkeepingError> {
+        let mut books = Books::default();
+        let mut ids: Vec<u16> = clients.keys().copied().collect();
+        ids.sort_unstable();
+        let mut lines = Vec::new();
+        for id in ids {
+            let client = &clients[&id];
+            lines.push((Account::HouseCash, client.total));
+            lines.push((
+                Account::ClientAvailable(id),
+                Amount::ZERO.checked_sub(client.available)?,
+            ));
+            lines.push((
+                Account::ClientHeld(id),
+                Amount::ZERO.checked_sub(client.held)?,
+            ));
+        }
+        lines.retain(|(_, amount)| *amount != Amount::ZERO);
+        if !lines.is_empty() {
+            books.post_lines(None, &lines)?;
+        }
+        Ok(books)
+    }
+
+    /// Every posting line in the order they were posted.
+    pub fn journal(&self) -> &[JournalEntry] {
+        &self.journal
+    }
+
+    /// Checks a transaction before the engine applies it, a posting that would leave
+    /// a held account negative rejects the transaction instead of leaving it half
+    /// applied. `transactions` is the state of the engine before applying it,
+    /// transactions the engine refuses anyway are left to the engine.
+    pub(crate) fn check(
+        &self,
+        transaction: &Transaction,
+        transactions: &HashMap<u32, StoredTransaction>,
+    ) -> Result<(), TransactionError> {
+        if matches!(
+            transaction.r#type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) {
+            return Ok(());
+        }
+        let applies = transactions.get(&transaction.tx).is_some_and(|stored| {
+            stored.client == transaction.client
+                && stored
+                    .state
+                    .transition(transaction.tx, &transaction.r#type)
+                    .is_ok()
+        });
+        if !applies {
+            return Ok(());
+        }
+        let held = Account::ClientHeld(transaction.client);
+        let mut held_balance = self.client_balance(held)?;
+        for (account, amount) in lines(transaction, transactions)? {
+            if account == held {
+                held_balance = held_balance.checked_sub(amount)?;
+            }
+        }
+        if held_balance < Amount::ZERO {
+            return Err(BookkeepingError::NegativeHeld(
+                transaction.tx,
+                transaction.client,
+                held_balance,
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Posts a transaction the engine just applied. `transactions` and `clients`
+    /// are the state of the engine after applying it.
+    pub(crate) fn post(
+        &mut self,
+        transaction: &Transaction,
+        transactions: &HashMap<u32, StoredTransaction>,
+        clients: &HashMap<u16, Client>,
+    ) -> Result<(), TransactionError> {
+        self.post_lines(Some(transaction.tx), &lines(transaction, transactions)?)?;
+
+        let id = transaction.client;
+        let matches = match clients.get(&id) {
+            Some(client) => {
+                self.client_balance(Account::ClientAvailable(id))? == client.available
+                    && self.client_balance(Account::ClientHeld(id))? == client.held
+            }
+            None => false,
+        };
+        if !matches {
+            return Err(BookkeepingError::Mismatch(transaction.tx, id).into());
+        }
+        Ok(())
+    }
+
+    /// Posts lines of signed amounts, positive amounts are debits and negative ones
+    /// credits. Nothing is posted unless there are debits and they equal the credits.
+    fn post_lines(
+        &mut self,
+        tx: Option<u32>,
+        lines: &[(Account, Amount)],
+    ) -> Result<(), BookkeepingError> {
+        let posting = self.postings;
+        let mut debits = Amount::ZERO;
+        let mut credits = Amount::ZERO;
+        for (_, amount) in lines {
+            if *amount >= Amount::ZERO {
+                debits = debits.checked_add(*amount)?;
+            } else {
+                credits = credits.checked_add(Amount::ZERO.checked_sub(*amount)?)?;
+            }
+        }
+        if debits == Amount::ZERO || debits != credits {
+            return Err(BookkeepingError::Unbalanced(posting, debits, credits));
+        }
+        let total_debits = self.debits.checked_add(debits)?;
+        let total_credits = self.credits.checked_add(credits)?;
+        // an account may appear on several lines
+        let mut balances: HashMap<Account, Amount> = HashMap::new();
+        for (account, amount) in lines {
+            let balance = match balances.get(account) {
+                Some(balance) => *balance,
+                None => self.balances.get(account).copied().unwrap_or_default(),
+            };
+            balances.insert(*account, balance.checked_add(*amount)?);
+        }
+
+        self.balances.extend(balances);
+        for (account, amount) in lines {
+            let (debit, credit) = if *amount >= Amount::ZERO {
+                (Some(*amount), None)
+            } else {
+                (None, Some(Amount::ZERO.checked_sub(*amount)?))
+            };
+            self.journal.push(JournalEntry {
+                posting,
+                tx,
+                account: *account,
+                debit,
+                credit,
+            });
+        }
+        self.debits = total_debits;
+        self.credits = total_credits;
+        self.postings += 1;
+        Ok(())
+    }
+
+    /// Balance of a client account, which as a liability has its credits positive.
+    fn client_balance(&self, account: Account) -> Result<Amount, BookkeepingError> {
+        let balance = self.balances.get(&account).copied().unwrap_or_default();
+        Ok(Amount::ZERO.checked_sub(balance)?)
+    }
+}
+
+/// The debit and the credit line of a transaction, `transactions` has to hold the
+/// transaction a dispute, resolve or chargeback refers to.
+fn lines(
+    transaction: &Transaction,
+    transactions: &HashMap<u32, StoredTransaction>,
+) -> Result<[(Account, Amount); 2], TransactionError> {
+    let id = transaction.client;
+    let available = Account::ClientAvailable(id);
+    let held = Account::ClientHeld(id);
+    let (debit, credit, amount) = match transaction.r#type {
+        TransactionType::Deposit => (Account::HouseCash, available, amount_of(transaction)?),
+        TransactionType::Withdrawal => (available, Account::HouseCash, amount_of(transaction)?),
+        TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+            let stored = transactions
+                .get(&transaction.tx)
+                .ok_or(TransactionError::TxNotFound(transaction.tx))?;
+            let is_deposit = match stored.r#type {
+                TransactionType::Deposit => true,
+                TransactionType::Withdrawal => false,
+                _ => {
+                    return Err(TransactionError::NotDisputable(
+                        transaction.tx,
+                        stored.r#type.clone(),
+                    ))
+                }
+            };
+            let (debit, credit) = match (&transaction.r#type, is_deposit) {
+                (TransactionType::Dispute, true) | (TransactionType::Resolve, false) => {
+                    (available, held)
+                }
+                (TransactionType::Dispute, false) | (TransactionType::Resolve, true) => {
+                    (held, available)
+                }
+                // the funds go back to the payer of the deposit
+                (TransactionType::Chargeback, true) => (held, Account::HouseCash),
+                // the withdrawal already left the house, it is paid out once more
+                _ => (Account::ChargebackLoss, held),
+            };
+            (debit, credit, stored.amount)
+        }
+    };
+    Ok([(debit, amount), (credit, Amount::ZERO.checked_sub(amount)?)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input_parser::InputParser;
+    use crate::transaction::tests::stream;
+    use crate::transaction_engine::{TransactionEngine, TransactionState};
+    use crate::validation::ValidationError;
+
+    fn balance(books: &Books, account: Account) -> Amount {
+        books.balances.get(&account).copied().unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_postings() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 2, "3".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 3, "1".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 2, None).unwrap(),
+            Transaction::new(TransactionType::Resolve, 1, 2, None).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None).unwrap(),
+        ];
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.books = Some(Books::new(&engine.clients).unwrap());
+        engine.process(stream(transactions)).await.unwrap();
+
+        let books = engine.books.as_ref().unwrap();
+        assert_eq!(books.postings, 7);
+        assert_eq!(books.debits, books.credits);
+        assert_eq!(balance(books, Account::HouseCash), "2".parse().unwrap());
+        assert_eq!(
+            balance(books, Account::ClientAvailable(1)),
+            "-2".parse().unwrap()
+        );
+        assert_eq!(balance(books, Account::ClientHeld(1)), Amount::ZERO);
+        assert_eq!(
+            &books.journal()[12..],
+            &[
+                JournalEntry {
+                    posting: 6,
+                    tx: Some(1),
+                    account: Account::ClientHeld(1),
+                    debit: "5".parse().ok(),
+                    credit: None,
+                },
+                JournalEntry {
+                    posting: 6,
+                    tx: Some(1),
+                    account: Account::HouseCash,
+                    debit: None,
+                    credit: "5".parse().ok(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_chargeback() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 2, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 2, None).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 3, "2".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 3, None).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 1, 3, None).unwrap(),
+        ];
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.books = Some(Books::new(&engine.clients).unwrap());
+        engine.process(stream(transactions)).await.unwrap();
+
+        let books = engine.books.as_ref().unwrap();
+        assert_eq!(
+            balance(books, Account::ChargebackLoss),
+            "2".parse().unwrap()
+        );
+        assert_eq!(balance(books, Account::HouseCash), "8".parse().unwrap());
+        assert_eq!(
+            books.client_balance(Account::ClientHeld(1)).unwrap(),
+            "5".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_unbalanced() {
+        let mut books = Books::default();
+        let error = books
+            .post_lines(None, &[(Account::HouseCash, "1".parse().unwrap())])
+            .unwrap_err();
+        assert_eq!(
+            error,
+            BookkeepingError::Unbalanced(0, "1".parse().unwrap(), Amount::ZERO)
+        );
+        let error = books
+            .post_lines(
+                Some(1),
+                &[
+                    (Account::HouseCash, "2".parse().unwrap()),
+                    (Account::ClientAvailable(1), "-1".parse().unwrap()),
+                ],
+            )
+            .unwrap_err();
+        assert_eq!(
+            error,
+            BookkeepingError::Unbalanced(0, "2".parse().unwrap(), "1".parse().unwrap())
+        );
+        // nothing of a refused posting is kept
+        assert!(books.balances.is_empty());
+        assert!(books.journal.is_empty());
+        assert_eq!(books.postings, 0);
+    }
+
+    #[test]
+    fn test_unchecked_transactions() {
+        let mut books = Books::default();
+        let mut transactions = HashMap::new();
+        let deposit = Transaction {
+            r#type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: None,
+        };
+        assert_eq!(
+            books.post(&deposit, &transactions, &HashMap::new()),
+            Err(ValidationError::MissingAmount(TransactionType::Deposit).into())
+        );
+        let dispute = Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap();
+        assert_eq!(
+            books.post(&dispute, &transactions, &HashMap::new()),
+            Err(TransactionError::TxNotFound(1))
+        );
+        transactions.insert(
+            1,
+            StoredTransaction {
+                client: 1,
+                r#type: TransactionType::Resolve,
+                amount: "1".parse().unwrap(),
+                state: TransactionState::Processed,
+            },
+        );
+        assert_eq!(
+            books.post(&dispute, &transactions, &HashMap::new()),
+            Err(TransactionError::NotDisputable(1, TransactionType::Resolve))
+        );
+        // a client the engine doesn't know can't match the books
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 2, "1".parse().ok()).unwrap();
+        assert_eq!(
+            books.post(&deposit, &transactions, &HashMap::new()),
+            Err(BookkeepingError::Mismatch(2, 1).into())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_set3_negative_held() {
+        let transactions = InputParser::new()
+            .unwrap()
+            .parse_transactions("data/set3.csv")
+            .await
+            .unwrap();
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.books = Some(Books::new(&engine.clients).unwrap());
+        engine.process(transactions).await.unwrap();
+
+        // the dispute is rejected before it touches the client
+        let rejected = &engine.rejected[0];
+        assert_eq!(rejected.transaction.tx, 2);
+        assert_eq!(
+            rejected.error.to_string(),
+            "transaction 2 leaves the held account of client 1 at -2.0000"
+        );
+        assert_eq!(rejected.error.code(), "negative_held");
+        let client = &engine.clients[&1];
+        assert_eq!(client.available, "8".parse().unwrap());
+        assert_eq!(client.held, Amount::ZERO);
+        assert_eq!(engine.transactions[&2].state, TransactionState::Processed);
+        let books = engine.books.as_ref().unwrap();
+        assert_eq!(books.postings, 6);
+        assert_eq!(balance(books, Account::ClientHeld(1)), Amount::ZERO);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_opening_balances() {
+        let transactions = InputParser::new()
+            .unwrap()
+            .parse_transactions("data/set1.csv")
+            .await
+            .unwrap();
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.process(transactions).await.unwrap();
+
+        let books = Books::new(&engine.clients).unwrap();
+        assert_eq!(books.postings, 1);
+        assert_eq!(balance(&books, Account::HouseCash), "3.5".parse().unwrap());
+        assert_eq!(
+            books.client_balance(Account::ClientAvailable(2)).unwrap(),
+            "2".parse().unwrap()
+        );
+    }
+}
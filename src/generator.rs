@@ -0,0 +1,159 @@
+use std::num::NonZeroU16;
+
+use crate::transaction::{Transaction, TransactionType};
+
+/// Deposits and withdrawals remembered per client as targets of disputes.
+const REMEMBERED_PER_CLIENT: usize = 64;
+/// Only every n-th client sees chargebacks, which lock its account for good. The
+/// others resolve their disputes instead, so most of the stream stays applicable.
+const CHARGEBACK_EVERY: u16 = 10;
+/// Largest generated amount in ten-thousandths, amounts range from 0.0001 to 999.9999.
+const MAX_AMOUNT: u32 = 9_999_999;
+
+/// Endless stream of pseudo random transactions, the same seed always gives the
+/// same transactions. Mostly deposits and withdrawals, disputes, resolves and
+/// chargebacks refer to earlier transactions of the same client and only a few
+/// clients ever get a chargeback. Ends once the transaction ids are used up.
+pub struct Generator {
+    state: u64,
+    clients: u16,
+    next_tx: Option<u32>,
+    owned: Vec<Vec<u32>>,
+}
+
+impl Generator {
+    /// Transactions of `clients` clients, the same `seed` always gives the same ones.
+    pub fn new(clients: NonZeroU16, seed: u64) -> Generator {
+        Generator {
+            state: seed,
+            clients: clients.get(),
+            next_tx: Some(1),
+            owned: vec![Vec::new(); clients.get() as usize],
+        }
+    }
+
+    /// Linear congruential generator, good enough for test data.
+    fn random(&mut self, bound: u32) -> u32 {
+        self.state = self
+            .state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        ((self.state >> 33) % bound as u64) as u32
+    }
+
+    fn remember(&mut self, client: u16, tx: u32) {
+        if self.owned[client as usize].len() < REMEMBERED_PER_CLIENT {
+            self.owned[client as usize].push(tx);
+        } else {
+            let slot = self.random(REMEMBERED_PER_CLIENT as u32) as usize;
+            self.owned[client as usize][slot] = tx;
+        }
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        let client = self.random(self.clients as u32) as u16;
+        let kind = self.random(100);
+        let owned = self.owned[client as usize].len();
+        if kind >= 85 && owned > 0 {
+            let index = self.random(owned as u32) as usize;
+            let tx = self.owned[client as usize][index];
+            let r#type = match kind {
+                85..=93 => TransactionType::Dispute,
+                97..=99 if client.is_multiple_of(CHARGEBACK_EVERY) => TransactionType::Chargeback,
+                _ => TransactionType::Resolve,
+            };
+            return Some(Transaction {
+                r#type,
+                client,
+                tx,
+                amount: None,
+            });
+        }
+
+        let tx = self.next_tx?;
+        self.next_tx = tx.checked_add(1);
+        let r#type = match kind {
+            0..=59 => TransactionType::Deposit,
+            _ => TransactionType::Withdrawal,
+        };
+        let units = 1 + self.random(MAX_AMOUNT);
+        let amount = format!("{}.{:04}", units / 10000, units % 10000)
+            .parse()
+            .expect("generated amounts are valid");
+        self.remember(client, tx);
+        Some(Transaction {
+            r#type,
+            client,
+            tx,
+            amount: Some(amount),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction_engine::TransactionEngine;
+    use crate::validation::validate;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_generator() {
+        let clients = NonZeroU16::new(20).unwrap();
+        let transactions: Vec<Transaction> = Generator::new(clients, 7).take(5000).collect();
+        assert_eq!(
+            transactions,
+            Generator::new(clients, 7).take(5000).collect::<Vec<_>>()
+        );
+        assert_ne!(
+            transactions,
+            Generator::new(clients, 8).take(5000).collect::<Vec<_>>()
+        );
+
+        let mut owners = HashMap::new();
+        for transaction in &transactions {
+            assert!(transaction.client < 20);
+            match transaction.r#type {
+                TransactionType::Deposit | TransactionType::Withdrawal => {
+                    let amount = transaction.amount.unwrap();
+                    assert!(amount >= "0.0001".parse().unwrap());
+                    assert!(amount <= "999.9999".parse().unwrap());
+                    assert!(owners.insert(transaction.tx, transaction.client).is_none());
+                }
+                TransactionType::Chargeback => {
+                    assert!(transaction.client.is_multiple_of(CHARGEBACK_EVERY));
+                    assert_eq!(owners[&transaction.tx], transaction.client);
+                }
+                _ => assert_eq!(owners[&transaction.tx], transaction.client),
+            }
+            assert!(validate(transaction).is_ok());
+        }
+
+        let mut engine = TransactionEngine::new().unwrap();
+        let applied = transactions
+            .iter()
+            .filter(|transaction| engine.apply(transaction).is_ok())
+            .count();
+        assert!(applied > 2500);
+        let locked = engine
+            .clients()
+            .values()
+            .filter(|client| client.locked())
+            .count();
+        assert!((1..=2).contains(&locked), "{} locked", locked);
+        let hit_locked = engine
+            .rejected()
+            .iter()
+            .filter(|rejection| rejection.error.code() == "account_locked")
+            .count();
+        assert!(
+            hit_locked < 500,
+            "{} transactions hit locked accounts",
+            hit_locked
+        );
+    }
+}
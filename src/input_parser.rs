@@ -1,48 +1,522 @@
 use crate::transaction::Transaction;
-use anyhow::Result;
-use std::{fs::File, io::BufRead, io::BufReader};
-use tokio::task::JoinHandle;
+use anyhow::{bail, Context, Result};
+use csv::StringRecord;
+use flate2::bufread::MultiGzDecoder;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::PathBuf,
+    sync::Arc,
+};
+use thiserror::Error;
+use tokio::{runtime::Handle, sync::mpsc, task::JoinHandle};
+use tokio_stream::wrappers::ReceiverStream;
 
+const CHUNK_SIZE: usize = 100000;
+// Upper bound for chunks read ahead of the consumer, this is what keeps memory
+// use independent of the input size.
+const MAX_PENDING_CHUNKS: usize = 8;
+/// Default for `InputParser::max_record_bytes`.
+pub const MAX_RECORD_BYTES: usize = 1 << 20;
+const REQUIRED_COLUMNS: [&str; 3] = ["type", "client", "tx"];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// Input name that stands for stdin.
+pub const STDIN: &str = "-";
+
+/// Transactions in input order, a malformed row ends it with an error in strict mode.
+pub type TransactionStream = ReceiverStream<Result<Transaction>>;
+
+type ChunkTask = Result<JoinHandle<Vec<Result<Transaction, ParseError>>>>;
+
+type Input = Box<dyn BufRead + Send>;
+
+/// A row of an input file that couldn't be turned into a transaction.
+#[derive(Debug, Error, Serialize, PartialEq, Eq, Clone)]
+#[error("{input}:{line}: {reason} ('{record}')")]
+pub struct ParseError {
+    /// Name of the input file, `-` for stdin.
+    pub input: String,
+    /// Line the row starts on, the header is line 1.
+    pub line: usize,
+    /// Why the row was refused.
+    pub reason: String,
+    /// The row as it was read.
+    pub record: String,
+}
+
+/// What happens to malformed rows.
+#[derive(Debug, Clone)]
+pub enum ParseMode {
+    /// Stop at the first malformed row.
+    Strict,
+    /// Skip malformed rows and write them to a csv file.
+    Lenient {
+        /// Where the malformed rows are written to.
+        rejects: PathBuf,
+    },
+}
+
+/// Reads transactions from csv files, see `parse_files`.
 #[derive(Debug)]
-pub struct InputParser {}
+pub struct InputParser {
+    mode: ParseMode,
+    max_record_bytes: usize,
+}
+
+/// Why a record couldn't be split off the input, it is only its first line then.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+enum RecordError {
+    #[error("unterminated quoted field")]
+    Unterminated,
+    #[error("record is longer than {0} bytes")]
+    TooLong(usize),
+}
 
-async fn deserialize_transactions(mut chunk: String) -> Vec<Transaction> {
-    chunk.retain(|c| c != ' ');
-    let mut csv: String = String::from("type,client,tx,amount\n");
-    if chunk.starts_with("type") {
-        panic!("First line was in chunk!!");
+/// A record of the input file as it was read, it spans several lines if a quoted
+/// field contains line breaks.
+#[derive(Debug)]
+struct RawRecord {
+    line: usize,
+    text: String,
+    error: Option<RecordError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QuoteState {
+    FieldStart,
+    Unquoted,
+    Quoted,
+    QuoteInQuoted,
+}
+
+/// Follows the quoting rules of the csv crate: only a quote at the very start of a
+/// field opens a quoted field, `""` in there is an escaped quote and quotes
+/// anywhere else are taken literally.
+fn scan_quotes(mut state: QuoteState, text: &str) -> QuoteState {
+    for byte in text.bytes() {
+        state = match (state, byte) {
+            (QuoteState::Quoted, b'"') => QuoteState::QuoteInQuoted,
+            (QuoteState::Quoted, _) => QuoteState::Quoted,
+            (QuoteState::QuoteInQuoted, b'"') => QuoteState::Quoted,
+            (QuoteState::FieldStart, b'"') => QuoteState::Quoted,
+            (_, b',') => QuoteState::FieldStart,
+            _ => QuoteState::Unquoted,
+        };
     }
-    csv.push_str(&chunk);
-    let mut rdr = csv::Reader::from_reader(csv.as_bytes());
-    let vec: Vec<Transaction> = rdr.deserialize().map(|t| t.unwrap()).collect();
-    vec
+    state
 }
 
-impl InputParser {
-    pub fn new() -> Result<InputParser> {
-        Ok(InputParser {})
-    }
-
-    pub async fn parse_transactions(self, file: &str) -> Result<Vec<Transaction>> {
-        let file = File::open(file)?;
-        let file = BufReader::new(file);
-        let mut input = String::new();
-        let mut tasks: Vec<JoinHandle<Vec<Transaction>>> = vec![];
-        for (i, line) in file.lines().enumerate().skip(1) {
-            input.extend(line);
-            input.push('\n');
-            if i % 100000 == 0 {
-                tasks.push(tokio::spawn(deserialize_transactions(input.clone())));
-                input = String::new();
+/// Splits the input into records, skipping blank lines and keeping track of the
+/// line number each record starts on. Line breaks inside quoted fields are kept as
+/// they are.
+struct RecordReader<R> {
+    input: R,
+    line: usize,
+    /// Upper bound for the length of a record, an unterminated quote must not
+    /// swallow the rest of the input.
+    limit: usize,
+    /// Lines read ahead for a broken record, they are read again as records of
+    /// their own.
+    pending: VecDeque<(usize, String)>,
+}
+
+impl<R: BufRead> RecordReader<R> {
+    fn new(input: R, limit: usize) -> RecordReader<R> {
+        RecordReader {
+            input,
+            line: 0,
+            limit,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// The next line including its line break. Of a line longer than the limit only
+    /// the first `limit + 1` bytes are kept.
+    fn next_line(&mut self) -> Option<io::Result<(usize, String)>> {
+        if let Some(line) = self.pending.pop_front() {
+            return Some(Ok(line));
+        }
+        let mut bytes = Vec::new();
+        let limit = self.limit as u64 + 1;
+        match (&mut self.input).take(limit).read_until(b'\n', &mut bytes) {
+            Ok(0) => return None,
+            Ok(_) => (),
+            Err(e) => return Some(Err(e)),
+        }
+        self.line += 1;
+        let text = if bytes.len() > self.limit && !bytes.ends_with(b"\n") {
+            if let Err(e) = skip_line(&mut self.input) {
+                return Some(Err(e));
+            }
+            String::from_utf8_lossy(&bytes).into_owned()
+        } else {
+            match String::from_utf8(bytes) {
+                Ok(text) => text,
+                Err(e) => return Some(Err(io::Error::new(io::ErrorKind::InvalidData, e))),
+            }
+        };
+        Some(Ok((self.line, text)))
+    }
+}
+
+fn skip_line(input: &mut impl BufRead) -> io::Result<()> {
+    loop {
+        let buffer = input.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        match buffer.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                input.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let length = buffer.len();
+                input.consume(length);
+            }
+        }
+    }
+}
+
+fn trim_line_break(mut text: String) -> String {
+    if text.ends_with('\n') {
+        text.pop();
+        if text.ends_with('\r') {
+            text.pop();
+        }
+    }
+    text
+}
+
+impl<R: BufRead> Iterator for RecordReader<R> {
+    type Item = io::Result<RawRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (line, mut text) = match self.next_line()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            if text.trim().is_empty() {
+                continue;
+            }
+            let mut length = text.len();
+            let mut state = scan_quotes(QuoteState::FieldStart, &text);
+            let mut continuation = Vec::new();
+            while state == QuoteState::Quoted && length <= self.limit {
+                match self.next_line() {
+                    Some(Ok((line, text))) => {
+                        length += text.len();
+                        state = scan_quotes(state, &text);
+                        continuation.push((line, text));
+                    }
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => break,
+                }
+            }
+            let error = if length > self.limit {
+                Some(RecordError::TooLong(self.limit))
+            } else if state == QuoteState::Quoted {
+                Some(RecordError::Unterminated)
+            } else {
+                None
+            };
+            if error.is_some() {
+                for line in continuation.drain(..).rev() {
+                    self.pending.push_front(line);
+                }
+            }
+            for (_, continued) in continuation {
+                text.push_str(&continued);
+            }
+            return Some(Ok(RawRecord {
+                line,
+                text: trim_line_break(text),
+                error,
+            }));
+        }
+    }
+}
+
+fn csv_reader(input: &str) -> csv::Reader<io::Cursor<&[u8]>> {
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(io::Cursor::new(input.as_bytes()))
+}
+
+/// An input file whose header was already read.
+struct Source {
+    name: Arc<str>,
+    records: RecordReader<Input>,
+    headers: StringRecord,
+}
+
+fn open_input(name: &str) -> Result<Input> {
+    if name == STDIN {
+        return Ok(Box::new(BufReader::new(io::stdin())));
+    }
+    let file = File::open(name).with_context(|| format!("Can't open input {}", name))?;
+    Ok(Box::new(BufReader::new(file)))
+}
+
+/// Decompresses gzip and zstd input while it is read. The format is detected from
+/// the magic bytes, the file extension only matters if there are none, then the
+/// decoder reports the broken input. Sniffing blocks until the input has data.
+fn decompress(name: &str, mut input: Input) -> Result<Input> {
+    let head = input.fill_buf()?;
+    let gzip =
+        head.starts_with(&GZIP_MAGIC) || (!head.starts_with(&ZSTD_MAGIC) && name.ends_with(".gz"));
+    let zstd = head.starts_with(&ZSTD_MAGIC) || (!gzip && name.ends_with(".zst"));
+    Ok(if gzip {
+        Box::new(BufReader::new(MultiGzDecoder::new(input)))
+    } else if zstd {
+        Box::new(BufReader::new(zstd::Decoder::with_buffer(input)?))
+    } else {
+        input
+    })
+}
+
+fn open_source(name: &str, input: Input, max_record_bytes: usize) -> Result<Source> {
+    let mut records = RecordReader::new(decompress(name, input)?, max_record_bytes);
+    let headers = match records.next() {
+        Some(header) => parse_headers(name, &header?)?,
+        None => bail!("Input {} is empty, expected at least a header", name),
+    };
+    Ok(Source {
+        name: name.into(),
+        records,
+        headers,
+    })
+}
+
+fn parse_headers(name: &str, record: &RawRecord) -> Result<StringRecord> {
+    let headers = csv_reader(&record.text)
+        .records()
+        .next()
+        .transpose()?
+        .unwrap_or_default();
+    for column in REQUIRED_COLUMNS {
+        if !headers.iter().any(|header| header == column) {
+            bail!("Header of input {} has no '{}' column", name, column);
+        }
+    }
+    Ok(headers)
+}
+
+fn parse_error(error: csv::Error, source: &str, record: &RawRecord) -> ParseError {
+    let reason = match error.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+        _ => error.to_string(),
+    };
+    ParseError {
+        input: source.to_owned(),
+        line: record.line,
+        reason,
+        record: record.text.clone(),
+    }
+}
+
+/// Every record is parsed from its own start with a fresh parser state, so a broken
+/// one can't affect its neighbours.
+fn deserialize_record(
+    reader: &mut csv::Reader<io::Cursor<&[u8]>>,
+    row: &mut StringRecord,
+    start: u64,
+    record: &RawRecord,
+    headers: &StringRecord,
+    source: &str,
+) -> Result<Transaction, ParseError> {
+    if let Some(error) = record.error {
+        return Err(ParseError {
+            input: source.to_owned(),
+            line: record.line,
+            reason: error.to_string(),
+            record: record.text.clone(),
+        });
+    }
+    let mut position = csv::Position::new();
+    position.set_byte(start);
+    reader
+        .seek_raw(io::SeekFrom::Start(start), position)
+        .and_then(|()| match reader.read_record(row) {
+            // columns are matched by their name in the header, so their order
+            // doesn't matter and unknown ones are ignored
+            Ok(true) => row.deserialize(Some(headers)),
+            Ok(false) => Err(csv::Error::from(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            ))),
+            Err(e) => Err(e),
+        })
+        .map_err(|e| parse_error(e, source, record))
+}
+
+async fn deserialize_transactions(
+    records: Vec<RawRecord>,
+    headers: Arc<StringRecord>,
+    source: Arc<str>,
+) -> Vec<Result<Transaction, ParseError>> {
+    let mut input = String::new();
+    let mut starts = Vec::with_capacity(records.len());
+    for record in &records {
+        starts.push(input.len() as u64);
+        input.push_str(&record.text);
+        input.push('\n');
+    }
+    let mut reader = csv_reader(&input);
+    let mut row = StringRecord::new();
+    records
+        .iter()
+        .zip(starts)
+        .map(|(record, start)| {
+            deserialize_record(&mut reader, &mut row, start, record, &headers, &source)
+        })
+        .collect()
+}
+
+/// Reads the sources one after the other, a chunk never spans two of them since
+/// their columns may differ.
+fn read_chunks(sources: Vec<Source>, runtime: Handle, chunks: mpsc::Sender<ChunkTask>) {
+    for source in sources {
+        let headers = Arc::new(source.headers);
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        for record in source.records {
+            match record {
+                Ok(record) => chunk.push(record),
+                Err(e) => {
+                    let error =
+                        anyhow::Error::from(e).context(format!("Can't read {}", source.name));
+                    let _ = chunks.blocking_send(Err(error));
+                    return;
+                }
+            }
+            if chunk.len() == CHUNK_SIZE {
+                let chunk = std::mem::replace(&mut chunk, Vec::with_capacity(CHUNK_SIZE));
+                let task = runtime.spawn(deserialize_transactions(
+                    chunk,
+                    headers.clone(),
+                    source.name.clone(),
+                ));
+                if chunks.blocking_send(Ok(task)).is_err() {
+                    // the stream was dropped, nobody is interested in the rest
+                    return;
+                }
             }
         }
         // deserialize the rest
-        tasks.push(tokio::spawn(deserialize_transactions(input)));
-        let mut output = vec![];
-        for task in tasks {
-            output.extend(task.await.unwrap());
+        let task = runtime.spawn(deserialize_transactions(chunk, headers, source.name));
+        if chunks.blocking_send(Ok(task)).is_err() {
+            return;
+        }
+    }
+}
+
+async fn forward_transactions(
+    mut chunks: mpsc::Receiver<ChunkTask>,
+    output: mpsc::Sender<Result<Transaction>>,
+    mut rejects: Option<csv::Writer<File>>,
+) {
+    while let Some(chunk) = chunks.recv().await {
+        let transactions = match chunk {
+            Ok(task) => task.await.map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        };
+        let transactions = match transactions {
+            Ok(transactions) => transactions,
+            Err(e) => {
+                let _ = output.send(Err(e)).await;
+                return;
+            }
+        };
+        for transaction in transactions {
+            let result = match (transaction, rejects.as_mut()) {
+                (Ok(transaction), _) => Ok(transaction),
+                (Err(e), Some(rejects)) => match rejects.serialize(e) {
+                    Ok(()) => continue,
+                    Err(e) => Err(e.into()),
+                },
+                (Err(e), None) => Err(e.into()),
+            };
+            let failed = result.is_err();
+            if output.send(result).await.is_err() || failed {
+                return;
+            }
+        }
+    }
+    if let Some(Err(e)) = rejects.as_mut().map(|r| r.flush()) {
+        let _ = output.send(Err(e.into())).await;
+    }
+}
+
+impl InputParser {
+    /// A parser in strict mode.
+    pub fn new() -> Result<InputParser> {
+        InputParser::with_mode(ParseMode::Strict)
+    }
+
+    /// A parser handling malformed rows according to `mode`.
+    pub fn with_mode(mode: ParseMode) -> Result<InputParser> {
+        Ok(InputParser {
+            mode,
+            max_record_bytes: MAX_RECORD_BYTES,
+        })
+    }
+
+    /// Longest record accepted, `MAX_RECORD_BYTES` by default. Longer ones are
+    /// malformed rows, so an unterminated quote can't swallow the rest of the input.
+    pub fn max_record_bytes(mut self, limit: usize) -> InputParser {
+        self.max_record_bytes = limit;
+        self
+    }
+
+    /// Streams the transactions of all files one after the other as if they were a
+    /// single input, each file has its own header. The headers of all files are
+    /// checked before the first transaction is streamed. Chunks of the files are
+    /// deserialized in parallel while the consumer is working on earlier ones.
+    pub async fn parse_files(self, files: &[&str]) -> Result<TransactionStream> {
+        if files.iter().filter(|&&file| file == STDIN).count() > 1 {
+            bail!("stdin can only be read once");
         }
-        Ok(output)
+        let files: Vec<String> = files.iter().map(|&file| file.to_owned()).collect();
+        let inputs = tokio::task::spawn_blocking(move || {
+            files
+                .into_iter()
+                .map(|file| {
+                    let input = open_input(&file)?;
+                    Ok((file, input))
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .await??;
+        self.parse_inputs(inputs).await
+    }
+
+    async fn parse_inputs(self, inputs: Vec<(String, Input)>) -> Result<TransactionStream> {
+        let limit = self.max_record_bytes;
+        // sniffing the compression and reading the headers blocks, stdin may take a
+        // while to deliver them
+        let (sources, rejects) = tokio::task::spawn_blocking(move || {
+            let sources = inputs
+                .into_iter()
+                .map(|(name, input)| open_source(&name, input, limit))
+                .collect::<Result<Vec<_>>>()?;
+            let rejects = match self.mode {
+                ParseMode::Strict => None,
+                ParseMode::Lenient { rejects } => Some(csv::Writer::from_path(rejects)?),
+            };
+            anyhow::Ok((sources, rejects))
+        })
+        .await??;
+        let (chunk_sender, chunk_receiver) = mpsc::channel(MAX_PENDING_CHUNKS);
+        let (sender, receiver) = mpsc::channel(CHUNK_SIZE);
+        let runtime = Handle::current();
+        tokio::task::spawn_blocking(move || read_chunks(sources, runtime, chunk_sender));
+        tokio::spawn(forward_transactions(chunk_receiver, sender, rejects));
+        Ok(ReceiverStream::new(receiver))
     }
 }
 
@@ -51,9 +525,22 @@ mod tests {
     use std::{path::Path, process::Command};
 
     use super::*;
+    use crate::transaction::tests::temp_path;
     use crate::transaction::TransactionType;
+    use tokio_stream::StreamExt;
+
+    impl InputParser {
+        /// Streams the transactions of a single file.
+        pub async fn parse_transactions(self, file: &str) -> Result<TransactionStream> {
+            self.parse_files(&[file]).await
+        }
+    }
+
+    async fn collect(stream: TransactionStream) -> Vec<Transaction> {
+        stream.collect::<Result<_>>().await.unwrap()
+    }
 
-    fn do_vecs_match<T: PartialEq>(a: &Vec<T>, b: &Vec<T>) -> bool {
+    fn do_vecs_match<T: PartialEq>(a: &[T], b: &[T]) -> bool {
         //TODO: What happens with NaN?
         let matching = a.iter().zip(b.iter()).filter(|&(a, b)| a == b).count();
         matching == a.len() && matching == b.len()
@@ -62,49 +549,51 @@ mod tests {
     #[tokio::test]
     async fn test_deserialize_set1() {
         let parser = InputParser::new().unwrap();
-        let output = parser.parse_transactions("data/set1.csv").await.unwrap();
-
-        let mut expected_output = Vec::<Transaction>::new();
-        expected_output.push(Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0)).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Deposit, 2, 2, Some(2.0)).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Deposit, 1, 3, Some(2.0)).unwrap());
-        expected_output
-            .push(Transaction::new(TransactionType::Withdrawal, 1, 4, Some(1.5)).unwrap());
-        expected_output
-            .push(Transaction::new(TransactionType::Withdrawal, 2, 5, Some(3.0)).unwrap());
+        let output = collect(parser.parse_transactions("data/set1.csv").await.unwrap()).await;
+
+        let expected_output = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "1.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 2, 2, "2.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 3, "2.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 4, "1.5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 2, 5, "3.0".parse().ok()).unwrap(),
+        ];
         assert!(do_vecs_match(&output, &expected_output));
     }
 
     #[tokio::test]
     async fn test_deserialize_set2() {
         let parser = InputParser::new().unwrap();
-        let output = parser.parse_transactions("data/set2.csv").await.unwrap();
-
-        let mut expected_output = Vec::<Transaction>::new();
-        expected_output.push(Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0)).unwrap());
-        expected_output
-            .push(Transaction::new(TransactionType::Withdrawal, 1, 2, Some(1.0)).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Resolve, 1, 1, None).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Chargeback, 1, 1, None).unwrap());
+        let output = collect(parser.parse_transactions("data/set2.csv").await.unwrap()).await;
+
+        let expected_output = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "1.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, "1.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Resolve, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None).unwrap(),
+        ];
         assert!(do_vecs_match(&output, &expected_output));
     }
 
     #[tokio::test]
     async fn test_deserialize_with_whitespace() {
         let parser = InputParser::new().unwrap();
-        let output = parser
-            .parse_transactions("data/set_whitespace.csv")
-            .await
-            .unwrap();
+        let output = collect(
+            parser
+                .parse_transactions("data/set_whitespace.csv")
+                .await
+                .unwrap(),
+        )
+        .await;
 
-        let mut expected_output = Vec::<Transaction>::new();
-        expected_output.push(Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0)).unwrap());
-        expected_output
-            .push(Transaction::new(TransactionType::Withdrawal, 1, 2, Some(1.0)).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Resolve, 1, 1, None).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Chargeback, 1, 1, None).unwrap());
+        let expected_output = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "1.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, "1.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Resolve, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None).unwrap(),
+        ];
         assert!(do_vecs_match(&output, &expected_output));
     }
 
@@ -120,8 +609,329 @@ mod tests {
                 .expect("Generation of file failed");
         }
         let parser = InputParser::new().unwrap();
-        let output = parser.parse_transactions("data/huge.csv").await.unwrap();
+        let output = collect(parser.parse_transactions("data/huge.csv").await.unwrap()).await;
 
         assert_eq!(output.len(), number_of_entries);
     }
+
+    #[tokio::test]
+    async fn test_strict_parse_error() {
+        let parser = InputParser::new().unwrap();
+        let mut stream = parser
+            .parse_transactions("data/set_malformed.csv")
+            .await
+            .unwrap();
+
+        assert_eq!(stream.next().await.unwrap().unwrap().tx, 1);
+        let error = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&ParseError {
+                input: "data/set_malformed.csv".to_owned(),
+                line: 3,
+                reason: "'abc' is not a valid amount".to_owned(),
+                record: "deposit,1,2,abc".to_owned(),
+            })
+        );
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lenient_parse_errors() {
+        let rejects = temp_path("rejects.csv");
+        let parser = InputParser::with_mode(ParseMode::Lenient {
+            rejects: rejects.clone(),
+        })
+        .unwrap();
+        let output = collect(
+            parser
+                .parse_transactions("data/set_malformed.csv")
+                .await
+                .unwrap(),
+        )
+        .await;
+        let txs: Vec<u32> = output.iter().map(|t| t.tx).collect();
+        assert_eq!(txs, vec![1, 5]);
+
+        let mut rdr = csv::Reader::from_path(&rejects).unwrap();
+        let lines: Vec<(usize, String)> = rdr
+            .deserialize()
+            .map(|r| {
+                let (_input, line, _reason, record): (String, usize, String, String) = r.unwrap();
+                (line, record)
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (3, "deposit,1,2,abc".to_owned()),
+                (5, "foo,1,3,1.0".to_owned()),
+                (6, "deposit,1".to_owned()),
+                (7, "deposit,1,4,1.00001".to_owned()),
+            ]
+        );
+        std::fs::remove_file(rejects).unwrap();
+    }
+
+    #[test]
+    fn test_record_lines() {
+        let input = "type,client,tx\r\n\r\n  \r\ndeposit,1,1,\"a\r\nb\"\r\nresolve,1,1\r\n";
+        let records: Vec<(usize, String)> = RecordReader::new(input.as_bytes(), MAX_RECORD_BYTES)
+            .map(|r| r.map(|r| (r.line, r.text)).unwrap())
+            .collect();
+        // line breaks inside quotes are part of the field
+        assert_eq!(
+            records,
+            vec![
+                (1, "type,client,tx".to_owned()),
+                (4, "deposit,1,1,\"a\r\nb\"".to_owned()),
+                (6, "resolve,1,1".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record_quotes() {
+        let records = |input: &str, limit| -> Vec<(usize, String, Option<RecordError>)> {
+            RecordReader::new(input.as_bytes(), limit)
+                .map(|r| r.map(|r| (r.line, r.text, r.error)).unwrap())
+                .collect()
+        };
+        // quotes inside unquoted fields are literal, escaped quotes don't close a field
+        assert_eq!(
+            records(
+                "deposit,1,2,2\"x\ndeposit,\"a\"\"\nb\",3\nresolve,1,1",
+                MAX_RECORD_BYTES
+            ),
+            vec![
+                (1, "deposit,1,2,2\"x".to_owned(), None),
+                (2, "deposit,\"a\"\"\nb\",3".to_owned(), None),
+                (4, "resolve,1,1".to_owned(), None),
+            ]
+        );
+        // an unterminated quote only breaks its own line
+        assert_eq!(
+            records("deposit,1,2,\"2.0\ndeposit,1,3,3.0", MAX_RECORD_BYTES),
+            vec![
+                (
+                    1,
+                    "deposit,1,2,\"2.0".to_owned(),
+                    Some(RecordError::Unterminated)
+                ),
+                (2, "deposit,1,3,3.0".to_owned(), None),
+            ]
+        );
+        // a quoted field may span any number of lines within the limit
+        let many = format!("a,\"b{}\",c\nd,e", "\nx".repeat(100));
+        let many = records(&many, MAX_RECORD_BYTES);
+        assert_eq!(many.len(), 2);
+        assert_eq!(many[0].2, None);
+        assert_eq!(many[1], (102, "d,e".to_owned(), None));
+
+        let long = records("a,\"b\nxxxxxxxxxx\nc,d\nxxxxxxxxxxxx\ne,f", 16);
+        assert_eq!(
+            long,
+            vec![
+                (1, "a,\"b".to_owned(), Some(RecordError::TooLong(16))),
+                (2, "xxxxxxxxxx".to_owned(), None),
+                (3, "c,d".to_owned(), None),
+                (4, "xxxxxxxxxxxx".to_owned(), None),
+                (5, "e,f".to_owned(), None),
+            ]
+        );
+        let long = records("a,b\nxxxxxxxxxxxxxxxxxxxxxxxx\nc,d", 16);
+        assert_eq!(long[1].0, 2);
+        assert_eq!(long[1].2, Some(RecordError::TooLong(16)));
+        assert_eq!(long[2], (3, "c,d".to_owned(), None));
+    }
+
+    #[tokio::test]
+    async fn test_stray_quotes() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,1,2,2\"x\n\
+                     deposit,1,3,3.0\n\
+                     deposit,1,4,\"4.0\n\
+                     deposit,1,5,5.0\n";
+        let mut stream = InputParser::new()
+            .unwrap()
+            .parse_inputs(vec![("stray.csv".to_owned(), Box::new(input.as_bytes()))])
+            .await
+            .unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().tx, 1);
+        let error = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "stray.csv:3: '2\"x' is not a valid amount ('deposit,1,2,2\"x')"
+        );
+
+        let rejects = temp_path("stray_quotes.csv");
+        let parser = InputParser::with_mode(ParseMode::Lenient {
+            rejects: rejects.clone(),
+        })
+        .unwrap();
+        let stream = parser
+            .parse_inputs(vec![("stray.csv".to_owned(), Box::new(input.as_bytes()))])
+            .await
+            .unwrap();
+        let txs: Vec<u32> = collect(stream).await.iter().map(|t| t.tx).collect();
+        assert_eq!(txs, vec![1, 3, 5]);
+        let reasons: Vec<(usize, String)> = csv::Reader::from_path(&rejects)
+            .unwrap()
+            .deserialize()
+            .map(|r| {
+                let (_input, line, reason, _record): (String, usize, String, String) = r.unwrap();
+                (line, reason)
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (3, "'2\"x' is not a valid amount".to_owned()),
+                (5, "unterminated quoted field".to_owned()),
+            ]
+        );
+        std::fs::remove_file(rejects).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_columns_by_name() {
+        let parser = InputParser::new().unwrap();
+        let output = collect(
+            parser
+                .parse_transactions("data/set_columns.csv")
+                .await
+                .unwrap(),
+        )
+        .await;
+
+        let expected_output = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "1.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 2, 2, "2.5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 2, 3, "0.5".parse().ok()).unwrap(),
+        ];
+        assert!(do_vecs_match(&output, &expected_output));
+    }
+
+    #[tokio::test]
+    async fn test_missing_column() {
+        let parser = InputParser::new().unwrap();
+        let error = parser
+            .parse_transactions("data/overdraft_limits.csv")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Header of input data/overdraft_limits.csv has no 'type' column"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multiple_files() {
+        let parser = InputParser::new().unwrap();
+        let output = collect(
+            parser
+                .parse_files(&["data/set1.csv", "data/set_columns.csv"])
+                .await
+                .unwrap(),
+        )
+        .await;
+        let txs: Vec<(u16, u32)> = output.iter().map(|t| (t.client, t.tx)).collect();
+        assert_eq!(
+            txs,
+            vec![
+                (1, 1),
+                (2, 2),
+                (1, 3),
+                (1, 4),
+                (2, 5),
+                (1, 1),
+                (2, 2),
+                (1, 1),
+                (2, 3)
+            ]
+        );
+
+        let parser = InputParser::new().unwrap();
+        let error = parser
+            .parse_files(&["data/set1.csv", "data/overdraft_limits.csv"])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Header of input data/overdraft_limits.csv has no 'type' column"
+        );
+        let parser = InputParser::new().unwrap();
+        let error = parser
+            .parse_files(&["-", "data/set1.csv", "-"])
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "stdin can only be read once");
+    }
+
+    #[tokio::test]
+    async fn test_compressed() {
+        for set in 1..=5 {
+            let plain = format!("data/set{}.csv", set);
+            let expected = collect(
+                InputParser::new()
+                    .unwrap()
+                    .parse_transactions(&plain)
+                    .await
+                    .unwrap(),
+            )
+            .await;
+            for extension in ["gz", "zst"] {
+                let compressed = format!("{}.{}", plain, extension);
+                let parser = InputParser::new().unwrap();
+                let output = collect(parser.parse_transactions(&compressed).await.unwrap()).await;
+                assert_eq!(output, expected, "{}", compressed);
+            }
+        }
+
+        // detected by the magic bytes alone
+        let parser = InputParser::new().unwrap();
+        let output = collect(parser.parse_transactions("data/set1_gz").await.unwrap()).await;
+        assert_eq!(output.len(), 5);
+
+        // stdin is sniffed the same way
+        let input: Input = Box::new(BufReader::new(File::open("data/set1.csv.gz").unwrap()));
+        let stream = InputParser::new()
+            .unwrap()
+            .parse_inputs(vec![(STDIN.to_owned(), input)])
+            .await
+            .unwrap();
+        assert_eq!(collect(stream).await.len(), 5);
+
+        let input: Input = Box::new("type,client,tx\n".as_bytes());
+        let error = InputParser::new()
+            .unwrap()
+            .parse_inputs(vec![("plain.csv.gz".to_owned(), input)])
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "invalid gzip header");
+    }
+
+    #[tokio::test]
+    async fn test_stdin() {
+        let stdin: Input = Box::new("type,client,tx,amount\ndeposit,1,1,x\n".as_bytes());
+        let file = open_input("data/set2.csv").unwrap();
+        let parser = InputParser::new().unwrap();
+        let mut stream = parser
+            .parse_inputs(vec![
+                ("data/set2.csv".to_owned(), file),
+                (STDIN.to_owned(), stdin),
+            ])
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            stream.next().await.unwrap().unwrap();
+        }
+        let error = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "-:2: 'x' is not a valid amount ('deposit,1,1,x')"
+        );
+    }
 }
@@ -0,0 +1,275 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::amount::Amount;
+use crate::output::Record;
+use crate::transaction::{Transaction, TransactionType};
+use crate::transaction_engine::Client;
+
+/// Movement of funds caused by an applied transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// Funds added to the available balance.
+    Deposit,
+    /// Funds taken from the available balance.
+    Withdrawal,
+    /// Funds moved between available and held by a dispute.
+    Hold,
+    /// Held funds moved back by a resolve.
+    Release,
+    /// Held funds removed by a chargeback.
+    Chargeback,
+}
+
+impl From<&TransactionType> for EventKind {
+    fn from(r#type: &TransactionType) -> EventKind {
+        match r#type {
+            TransactionType::Deposit => EventKind::Deposit,
+            TransactionType::Withdrawal => EventKind::Withdrawal,
+            TransactionType::Dispute => EventKind::Hold,
+            TransactionType::Resolve => EventKind::Release,
+            TransactionType::Chargeback => EventKind::Chargeback,
+        }
+    }
+}
+
+/// One balance change of a client. The balances before and after are stored next to
+/// each other instead of nested, so that the event is a flat csv row.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LedgerEvent {
+    /// Position of the event in the ledger, starting at 0.
+    pub sequence: u64,
+    /// Transaction that caused the change.
+    pub tx: u32,
+    /// Client whose balances changed.
+    pub client: u16,
+    /// What the change was.
+    pub kind: EventKind,
+    /// Amount of the transaction.
+    pub amount: Amount,
+    /// Available funds before the change.
+    pub available_before: Amount,
+    /// Available funds after the change.
+    pub available_after: Amount,
+    /// Held funds before the change.
+    pub held_before: Amount,
+    /// Held funds after the change.
+    pub held_after: Amount,
+    /// Total funds before the change.
+    pub total_before: Amount,
+    /// Total funds after the change.
+    pub total_after: Amount,
+}
+
+impl Record for LedgerEvent {
+    const COLUMNS: &'static [&'static str] = &[
+        "sequence",
+        "tx",
+        "client",
+        "kind",
+        "amount",
+        "available_before",
+        "available_after",
+        "held_before",
+        "held_after",
+        "total_before",
+        "total_after",
+    ];
+}
+
+/// Balances of a client rebuilt from the ledger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Balance {
+    /// Funds that can be withdrawn.
+    pub available: Amount,
+    /// Funds held by disputes.
+    pub held: Amount,
+    /// Available and held funds.
+    pub total: Amount,
+}
+
+/// Append-only list of every balance change the engine applied, in stream order.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    events: Vec<LedgerEvent>,
+}
+
+impl Ledger {
+    /// Every balance change in the order it was applied.
+    pub fn events(&self) -> &[LedgerEvent] {
+        &self.events
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        transaction: &Transaction,
+        amount: Amount,
+        before: &Client,
+        after: &Client,
+    ) {
+        self.events.push(LedgerEvent {
+            sequence: self.events.len() as u64,
+            tx: transaction.tx,
+            client: transaction.client,
+            kind: EventKind::from(&transaction.r#type),
+            amount,
+            available_before: before.available,
+            available_after: after.available,
+            held_before: before.held,
+            held_after: after.held,
+            total_before: before.total,
+            total_after: after.total,
+        });
+    }
+
+    /// Rebuilds the balance of `client` after all its events up to and including
+    /// `sequence` by replaying them, `None` if the client has no events yet. A ledger
+    /// of an engine restored from a snapshot starts at the balances of the snapshot.
+    /// Every event has to start where the previous one of the client ended,
+    /// otherwise the ledger is incomplete or was tampered with.
+    pub fn balance_at(&self, client: u16, sequence: u64) -> Result<Option<Balance>> {
+        let mut balance: Option<Balance> = None;
+        for event in self
+            .events
+            .iter()
+            .take_while(|e| e.sequence <= sequence)
+            .filter(|e| e.client == client)
+        {
+            let before = Balance {
+                available: event.available_before,
+                held: event.held_before,
+                total: event.total_before,
+            };
+            if balance.is_some_and(|b| b != before) {
+                bail!(
+                    "Ledger event {} of client {} doesn't continue the previous balance",
+                    event.sequence,
+                    client
+                );
+            }
+            balance = Some(Balance {
+                available: event.available_after,
+                held: event.held_after,
+                total: event.total_after,
+            });
+        }
+        Ok(balance)
+    }
+
+    /// Checks that replaying the ledger ends at the current balances of the clients.
+    pub fn verify(&self, clients: &HashMap<u16, Client>) -> Result<()> {
+        for (id, client) in clients {
+            if let Some(balance) = self.balance_at(*id, u64::MAX)? {
+                let current = Balance {
+                    available: client.available,
+                    held: client.held,
+                    total: client.total,
+                };
+                if balance != current {
+                    bail!("Ledger of client {} doesn't match its balance", id);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{write_records, OutputFormat};
+    use crate::transaction::tests::stream;
+    use crate::transaction_engine::TransactionEngine;
+
+    async fn engine() -> TransactionEngine {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, "2".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 2, 3, "1".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 4, "9".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 2, None).unwrap(),
+            Transaction::new(TransactionType::Resolve, 1, 2, None).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None).unwrap(),
+        ];
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.ledger = Some(Ledger::default());
+        engine.process(stream(transactions)).await.unwrap();
+        engine
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let engine = engine().await;
+        let events = engine.ledger.as_ref().unwrap().events();
+        let kinds: Vec<_> = events.iter().map(|e| (e.tx, e.kind)).collect();
+        // the rejected withdrawal 4 leaves no trace
+        assert_eq!(
+            kinds,
+            vec![
+                (1, EventKind::Deposit),
+                (2, EventKind::Withdrawal),
+                (3, EventKind::Deposit),
+                (2, EventKind::Hold),
+                (2, EventKind::Release),
+                (1, EventKind::Hold),
+                (1, EventKind::Chargeback),
+            ]
+        );
+        let hold = &events[5];
+        assert_eq!(hold.amount, "5".parse().unwrap());
+        assert_eq!(hold.available_before, "3".parse().unwrap());
+        assert_eq!(hold.available_after, "-2".parse().unwrap());
+        assert_eq!(hold.held_after, "5".parse().unwrap());
+        assert_eq!(hold.total_after, "3".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_balance_at() {
+        let engine = engine().await;
+        let ledger = engine.ledger.as_ref().unwrap();
+        let balance = |client, sequence| ledger.balance_at(client, sequence).unwrap().unwrap();
+        assert_eq!(balance(1, 0).available, "5".parse().unwrap());
+        assert_eq!(balance(1, 2).available, "3".parse().unwrap());
+        // withdrawal 2 in dispute, the README assumption moves it from held to available
+        assert_eq!(balance(1, 3).available, "5".parse().unwrap());
+        assert_eq!(balance(1, 3).held, "-2".parse().unwrap());
+        assert_eq!(balance(2, 6).total, "1".parse().unwrap());
+        assert_eq!(ledger.balance_at(2, 1).unwrap(), None);
+        assert_eq!(ledger.balance_at(3, 6).unwrap(), None);
+        ledger.verify(&engine.clients).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tampered_ledger() {
+        let mut engine = engine().await;
+        let ledger = engine.ledger.as_mut().unwrap();
+        ledger.events.remove(1);
+        assert_eq!(
+            ledger.balance_at(1, 10).unwrap_err().to_string(),
+            "Ledger event 3 of client 1 doesn't continue the previous balance"
+        );
+        assert!(ledger.balance_at(2, 10).is_ok());
+        assert!(ledger.verify(&engine.clients).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let engine = engine().await;
+        let mut output = Vec::new();
+        write_records(
+            &engine.ledger.as_ref().unwrap().events()[..2],
+            OutputFormat::Csv,
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "sequence,tx,client,kind,amount,available_before,available_after,held_before,held_after,total_before,total_after\n\
+             0,1,1,deposit,5.0000,0.0000,5.0000,0.0000,0.0000,0.0000,5.0000\n\
+             1,2,1,withdrawal,2.0000,5.0000,3.0000,0.0000,0.0000,5.0000,3.0000\n"
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! Processes deposits, withdrawals and disputes of client accounts.
+//!
+//! ```
+//! use kraken::{Transaction, TransactionEngine, TransactionType};
+//!
+//! let mut engine = TransactionEngine::builder().build()?;
+//! engine.apply(&Transaction {
+//!     r#type: TransactionType::Deposit,
+//!     client: 1,
+//!     tx: 1,
+//!     amount: Some("1.5".parse()?),
+//! })?;
+//! assert!(engine
+//!     .apply(&Transaction {
+//!         r#type: TransactionType::Withdrawal,
+//!         client: 1,
+//!         tx: 2,
+//!         amount: Some("2".parse()?),
+//!     })
+//!     .is_err());
+//! assert_eq!(engine.client(1).unwrap().available(), "1.5".parse()?);
+//! assert_eq!(engine.rejected().len(), 1);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+#![warn(missing_docs)]
+
+/// Fixed point amounts with four decimal places.
+pub mod amount;
+/// HTTP/JSON API over a shared engine.
+#[cfg(feature = "api")]
+pub mod api;
+/// Double-entry books kept next to the engine.
+pub mod bookkeeping;
+/// Reproducible random transactions for load tests.
+pub mod generator;
+/// Reading transactions from csv files, compressed or not, and stdin.
+pub mod input_parser;
+/// Audit trail of every balance change.
+pub mod ledger;
+/// Writing clients and reports as csv, json or tables.
+pub mod output;
+/// Per-client settings like overdraft limits.
+pub mod policy;
+/// Line based TCP server applying live transactions.
+#[cfg(feature = "server")]
+pub mod server;
+/// Processing the clients in parallel.
+pub mod sharding;
+mod snapshot;
+/// Figures about an engine and the transactions it processed.
+pub mod stats;
+/// Transactions as they are read from the input.
+pub mod transaction;
+/// The engine applying transactions to client accounts.
+pub mod transaction_engine;
+/// Checks a transaction has to pass before it is applied.
+pub mod validation;
+mod wal;
+
+pub use amount::Amount;
+pub use input_parser::InputParser;
+pub use policy::Policy;
+pub use transaction::{Transaction, TransactionType};
+pub use transaction_engine::{
+    ApplyError, Client, ClientOrder, TransactionEngine, TransactionEngineBuilder, TransactionError,
+};
@@ -1,22 +1,577 @@
-mod input_parser;
-mod transaction;
-mod transaction_engine;
+use anyhow::anyhow;
+use clap::{ArgGroup, Parser, Subcommand};
+#[cfg(feature = "api")]
+use kraken::api;
+use kraken::generator::Generator;
+use kraken::input_parser::{self, ParseMode};
+use kraken::output::{self, ClientRecord, OutputFormat, Record, RejectionRecord};
+#[cfg(feature = "server")]
+use kraken::server;
+use kraken::stats::TypeCounts;
+use kraken::{sharding, validation};
+use kraken::{ClientOrder, InputParser, Policy, Transaction, TransactionEngine};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    num::{NonZeroU16, NonZeroUsize},
+    path::PathBuf,
+    process::ExitCode,
+};
+use tokio::net::TcpListener;
+use tokio_stream::{Stream, StreamExt};
 
-use input_parser::InputParser;
-use transaction_engine::TransactionEngine;
+/// Exit codes of the failure modes, clap exits with 2 for invalid arguments.
+const EXIT_FAILURE: u8 = 1;
+const EXIT_INPUT: u8 = 3;
+const EXIT_STATE: u8 = 4;
+const EXIT_OUTPUT: u8 = 5;
+const EXIT_INVALID: u8 = 6;
 
-use std::env;
+/// Processes csv files of transactions and prints the resulting client balances.
+///
+/// Exit codes: 0 on success, 1 if processing failed (e.g. the books don't add up),
+/// 2 for invalid arguments, 3 if the input can't be read or is malformed, 4 if the
+/// overdraft limits, a snapshot or the write-ahead log can't be loaded or saved, 5
+/// if an output can't be written and 6 if `validate` found invalid transactions.
+#[derive(Debug, Parser)]
+#[clap(version)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Apply transactions and print the resulting client balances
+    Process(ProcessArgs),
+    /// Parse and check transactions without applying them
+    Validate(InputArgs),
+    /// Apply transactions and print statistics about them
+    Stats(StatsArgs),
+    /// Apply transactions and save the resulting state to a snapshot
+    Snapshot(SnapshotArgs),
+    /// Print the client balances stored in a snapshot
+    Restore(RestoreArgs),
+    /// Apply live transactions received over TCP or HTTP until interrupted
+    Serve(ServeArgs),
+    /// Write pseudo random transactions as csv, e.g. for load tests
+    Generate(GenerateArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct InputArgs {
+    /// csv files with the transactions, processed in order as one stream. Reads
+    /// stdin if there is none or for `-`
+    files: Vec<String>,
+    /// Skip malformed rows and write them to this csv file instead of aborting
+    #[clap(long, value_name = "FILE")]
+    rejects: Option<PathBuf>,
+    /// Rows longer than this are malformed, e.g. if a quoted field is never closed
+    #[clap(long, value_name = "BYTES", default_value_t = input_parser::MAX_RECORD_BYTES)]
+    max_record_bytes: usize,
+}
+
+#[derive(Debug, clap::Args)]
+struct EngineArgs {
+    /// csv file granting overdraft limits per client, with the columns `client,limit`
+    #[clap(long, value_name = "FILE")]
+    overdraft_limits: Option<String>,
+    /// Resume from the engine state in this snapshot instead of starting empty
+    #[clap(long, value_name = "FILE")]
+    load_snapshot: Option<PathBuf>,
+    /// Replay this write-ahead log on startup and append accepted transactions to it,
+    /// it is cleared once a snapshot is saved
+    #[clap(long, value_name = "FILE")]
+    wal: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+struct ClientArgs {
+    /// Order of the printed clients, balances are listed from largest to smallest
+    #[clap(long, possible_values = ClientOrder::NAMES, default_value = "id")]
+    sort_by: ClientOrder,
+    /// Format of the printed clients
+    #[clap(long, possible_values = OutputFormat::NAMES, default_value = "csv")]
+    format: OutputFormat,
+    /// Write the clients to this file instead of stdout
+    #[clap(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+struct ReportArgs {
+    /// Write a report of all rejected transactions with their reason to this file
+    #[clap(long, value_name = "FILE")]
+    rejections: Option<PathBuf>,
+    /// Format of the rejections report
+    #[clap(long, possible_values = OutputFormat::NAMES, default_value = "csv")]
+    rejections_format: OutputFormat,
+    /// Save the engine state after processing to this snapshot file
+    #[clap(long, value_name = "FILE")]
+    save_snapshot: Option<PathBuf>,
+    /// Write an audit ledger of every balance change to this file
+    #[clap(long, value_name = "FILE")]
+    ledger: Option<PathBuf>,
+    /// Format of the audit ledger
+    #[clap(long, possible_values = OutputFormat::NAMES, default_value = "csv")]
+    ledger_format: OutputFormat,
+    /// Post every transaction to double-entry books, stop if they don't add up,
+    /// and write the journal to this file
+    #[clap(long, value_name = "FILE")]
+    journal: Option<PathBuf>,
+    /// Format of the journal
+    #[clap(long, possible_values = OutputFormat::NAMES, default_value = "csv")]
+    journal_format: OutputFormat,
+}
+
+#[derive(Debug, clap::Args)]
+struct ProcessArgs {
+    #[clap(flatten)]
+    input: InputArgs,
+    #[clap(flatten)]
+    engine: EngineArgs,
+    #[clap(flatten)]
+    reports: ReportArgs,
+    #[clap(flatten)]
+    clients: ClientArgs,
+    /// Apply the transactions of different clients in parallel on this many threads
+    #[clap(long, value_name = "N", conflicts_with_all = &["wal", "ledger", "journal"])]
+    shards: Option<NonZeroUsize>,
+}
+
+#[derive(Debug, clap::Args)]
+struct StatsArgs {
+    #[clap(flatten)]
+    input: InputArgs,
+    #[clap(flatten)]
+    engine: EngineArgs,
+    /// Format of the statistics
+    #[clap(long, possible_values = OutputFormat::NAMES, default_value = "table")]
+    format: OutputFormat,
+    /// Write the statistics to this file instead of stdout
+    #[clap(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+struct SnapshotArgs {
+    #[clap(flatten)]
+    input: InputArgs,
+    #[clap(flatten)]
+    engine: EngineArgs,
+    /// Snapshot file to save the state to
+    #[clap(long, short, value_name = "FILE")]
+    output: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+struct RestoreArgs {
+    /// Snapshot file to restore
+    snapshot: PathBuf,
+    /// Replay this write-ahead log on top of the snapshot, the log is only read
+    #[clap(long, value_name = "FILE")]
+    wal: Option<PathBuf>,
+    #[clap(flatten)]
+    clients: ClientArgs,
+}
+
+#[derive(Debug, clap::Args)]
+#[clap(group(ArgGroup::new("address").required(true).args(&["listen", "http"])))]
+struct ServeArgs {
+    /// Accept newline-delimited csv or json transactions and `query <client>`
+    /// requests on this address
+    #[clap(long, value_name = "ADDRESS")]
+    listen: Option<SocketAddr>,
+    /// Serve the HTTP/JSON API on this address
+    #[clap(long, value_name = "ADDRESS")]
+    http: Option<SocketAddr>,
+    #[clap(flatten)]
+    engine: EngineArgs,
+    #[clap(flatten)]
+    reports: ReportArgs,
+    #[clap(flatten)]
+    clients: ClientArgs,
+}
+
+#[derive(Debug, clap::Args)]
+struct GenerateArgs {
+    /// Number of transactions
+    #[clap(long, default_value = "1000")]
+    count: usize,
+    /// Number of clients, ids start at 0
+    #[clap(long, default_value = "100")]
+    clients: NonZeroU16,
+    /// The same seed always generates the same transactions
+    #[clap(long, default_value = "0")]
+    seed: u64,
+    /// Write the transactions to this file instead of stdout
+    #[clap(long, short, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+/// An error together with the code the program exits with.
+struct Failure {
+    code: u8,
+    error: anyhow::Error,
+}
+
+trait ExitWith<T> {
+    fn exit_with(self, code: u8) -> Result<T, Failure>;
+}
+
+impl<T, E: Into<anyhow::Error>> ExitWith<T> for Result<T, E> {
+    fn exit_with(self, code: u8) -> Result<T, Failure> {
+        self.map_err(|e| Failure {
+            code,
+            error: e.into(),
+        })
+    }
+}
+
+/// Marks errors of the transaction stream, so that they can be told apart from
+/// failures of the engine.
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+struct InputError(anyhow::Error);
+
+fn processing_failure(error: anyhow::Error) -> Failure {
+    let code = match error.is::<InputError>() {
+        true => EXIT_INPUT,
+        false => EXIT_FAILURE,
+    };
+    Failure { code, error }
+}
+
+async fn read_transactions(
+    args: InputArgs,
+) -> Result<impl Stream<Item = anyhow::Result<Transaction>>, Failure> {
+    let parser = match args.rejects {
+        Some(rejects) => InputParser::with_mode(ParseMode::Lenient { rejects }),
+        None => InputParser::new(),
+    }
+    .exit_with(EXIT_INPUT)?
+    .max_record_bytes(args.max_record_bytes);
+    let files: Vec<&str> = match args.files.is_empty() {
+        true => vec![input_parser::STDIN],
+        false => args.files.iter().map(String::as_str).collect(),
+    };
+    let transactions = parser.parse_files(&files).await.exit_with(EXIT_INPUT)?;
+    Ok(transactions.map(|transaction| transaction.map_err(|e| InputError(e).into())))
+}
+
+fn build_engine(
+    args: EngineArgs,
+    reports: Option<&ReportArgs>,
+) -> Result<TransactionEngine, Failure> {
+    let mut builder = TransactionEngine::builder();
+    if let Some(reports) = reports {
+        builder = builder
+            .ledger(reports.ledger.is_some())
+            .journal(reports.journal.is_some());
+    }
+    if let Some(file) = &args.overdraft_limits {
+        builder = builder.policy(Policy::from_file(file).exit_with(EXIT_STATE)?);
+    }
+    if let Some(path) = args.load_snapshot {
+        builder = builder.snapshot(path);
+    }
+    if let Some(path) = args.wal {
+        builder = builder.wal(path);
+    }
+    let engine = builder.build().exit_with(EXIT_STATE)?;
+    report_recovery(&engine);
+    Ok(engine)
+}
+
+fn report_recovery(engine: &TransactionEngine) {
+    let recovery = engine.recovery();
+    if recovery.discarded > 0 {
+        eprintln!(
+            "Discarded {} bytes of a torn record at the end of the write-ahead log",
+            recovery.discarded
+        );
+    }
+    if recovery.diverged > 0 {
+        eprintln!(
+            "{} records of the write-ahead log were accepted or rejected differently than logged",
+            recovery.diverged
+        );
+    }
+}
+
+fn write_output<R: Record>(
+    records: &[R],
+    format: OutputFormat,
+    path: Option<&std::path::Path>,
+) -> Result<(), Failure> {
+    let mut output = output::open_output(path).exit_with(EXIT_OUTPUT)?;
+    output::write_records(records, format, &mut output).exit_with(EXIT_OUTPUT)
+}
+
+fn log_rejections(engine: &TransactionEngine) {
+    for rejected in engine.rejected() {
+        eprintln!(
+            "Rejected transaction {}: {}",
+            rejected.transaction.tx, rejected.error
+        );
+    }
+}
+
+fn write_reports(engine: &mut TransactionEngine, args: ReportArgs) -> Result<(), Failure> {
+    if let Some(path) = args.save_snapshot {
+        engine.save_snapshot(&path).exit_with(EXIT_STATE)?;
+    }
+    if let (Some(path), Some(ledger)) = (args.ledger, engine.ledger()) {
+        ledger.verify(engine.clients()).exit_with(EXIT_FAILURE)?;
+        write_output(ledger.events(), args.ledger_format, Some(&path))?;
+    }
+    if let (Some(path), Some(books)) = (args.journal, engine.books()) {
+        write_output(books.journal(), args.journal_format, Some(&path))?;
+    }
+    match args.rejections {
+        Some(path) => {
+            let rejections: Vec<RejectionRecord> =
+                engine.rejected().iter().map(RejectionRecord::new).collect();
+            write_output(&rejections, args.rejections_format, Some(&path))
+        }
+        None => {
+            log_rejections(engine);
+            Ok(())
+        }
+    }
+}
+
+fn write_clients(engine: &TransactionEngine, args: ClientArgs) -> Result<(), Failure> {
+    let clients: Vec<ClientRecord> = engine
+        .sorted_clients(args.sort_by)
+        .into_iter()
+        .map(|(id, client)| ClientRecord::new(id, client))
+        .collect();
+    write_output(&clients, args.format, args.output.as_deref())
+}
+
+async fn process(args: ProcessArgs) -> Result<(), Failure> {
+    let mut engine = build_engine(args.engine, Some(&args.reports))?;
+    let transactions = read_transactions(args.input).await?;
+    match args.shards {
+        Some(shards) => sharding::process(&mut engine, transactions, shards).await,
+        None => engine.process(transactions).await,
+    }
+    .map_err(processing_failure)?;
+    write_reports(&mut engine, args.reports)?;
+    write_clients(&engine, args.clients)
+}
+
+async fn validate_transactions(args: InputArgs) -> Result<(), Failure> {
+    let transactions = read_transactions(args).await?;
+    let check = validation::check_stream(transactions, |transaction, error| {
+        eprintln!("Invalid transaction {}: {}", transaction.tx, error)
+    })
+    .await
+    .exit_with(EXIT_INPUT)?;
+    println!(
+        "{} transactions, {} invalid",
+        check.transactions, check.invalid
+    );
+    if check.invalid > 0 {
+        return Err(anyhow!(
+            "{} of {} transactions are invalid",
+            check.invalid,
+            check.transactions
+        ))
+        .exit_with(EXIT_INVALID);
+    }
+    Ok(())
+}
+
+async fn stats(args: StatsArgs) -> Result<(), Failure> {
+    let mut engine = build_engine(args.engine, None)?;
+    let mut counts = TypeCounts::default();
+    let transactions = read_transactions(args.input).await?.map(|transaction| {
+        if let Ok(transaction) = &transaction {
+            counts.add(&transaction.r#type);
+        }
+        transaction
+    });
+    engine
+        .process(transactions)
+        .await
+        .map_err(processing_failure)?;
+    log_rejections(&engine);
+    let stats = engine.stats().exit_with(EXIT_FAILURE)?;
+    write_output(&stats.records(&counts), args.format, args.output.as_deref())
+}
+
+async fn snapshot(args: SnapshotArgs) -> Result<(), Failure> {
+    let mut engine = build_engine(args.engine, None)?;
+    let transactions = read_transactions(args.input).await?;
+    engine
+        .process(transactions)
+        .await
+        .map_err(processing_failure)?;
+    log_rejections(&engine);
+    engine.save_snapshot(&args.output).exit_with(EXIT_STATE)?;
+    eprintln!(
+        "Saved {} clients to {}",
+        engine.clients().len(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+fn restore(args: RestoreArgs) -> Result<(), Failure> {
+    let mut builder = TransactionEngine::builder().snapshot(args.snapshot);
+    if let Some(path) = args.wal {
+        builder = builder.replay(path);
+    }
+    let engine = builder.build().exit_with(EXIT_STATE)?;
+    report_recovery(&engine);
+    write_clients(&engine, args.clients)
+}
+
+async fn serve(args: ServeArgs) -> Result<(), Failure> {
+    let mut engine = build_engine(args.engine, Some(&args.reports))?;
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    let (address, http) = match (args.listen, args.http) {
+        (Some(address), _) => (address, false),
+        (None, Some(address)) => (address, true),
+        (None, None) => unreachable!("clap requires an address"),
+    };
+    let listener = TcpListener::bind(address).await.exit_with(EXIT_FAILURE)?;
+    let local = listener.local_addr().exit_with(EXIT_FAILURE)?;
+    engine = match http {
+        true => {
+            eprintln!("Serving http on {}", local);
+            serve_http(listener, engine, shutdown).await
+        }
+        false => {
+            eprintln!("Listening on {}", local);
+            serve_lines(listener, engine, shutdown).await
+        }
+    }
+    .exit_with(EXIT_FAILURE)?;
+    write_reports(&mut engine, args.reports)?;
+    write_clients(&engine, args.clients)
+}
+
+#[cfg(feature = "api")]
+async fn serve_http(
+    listener: TcpListener,
+    engine: TransactionEngine,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<TransactionEngine> {
+    api::serve(listener, engine, shutdown).await
+}
+
+#[cfg(not(feature = "api"))]
+async fn serve_http(
+    _listener: TcpListener,
+    _engine: TransactionEngine,
+    _shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<TransactionEngine> {
+    Err(anyhow!(
+        "--http needs kraken to be built with the `api` feature"
+    ))
+}
+
+#[cfg(feature = "server")]
+async fn serve_lines(
+    listener: TcpListener,
+    engine: TransactionEngine,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<TransactionEngine> {
+    server::serve(listener, engine, shutdown).await
+}
+
+#[cfg(not(feature = "server"))]
+async fn serve_lines(
+    _listener: TcpListener,
+    _engine: TransactionEngine,
+    _shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<TransactionEngine> {
+    Err(anyhow!(
+        "--listen needs kraken to be built with the `server` feature"
+    ))
+}
+
+fn generate(args: GenerateArgs) -> Result<(), Failure> {
+    let output = output::open_output(args.output.as_deref()).exit_with(EXIT_OUTPUT)?;
+    let mut writer = csv::Writer::from_writer(output);
+    for transaction in Generator::new(args.clients, args.seed).take(args.count) {
+        writer.serialize(transaction).exit_with(EXIT_OUTPUT)?;
+    }
+    writer.flush().exit_with(EXIT_OUTPUT)
+}
 
 #[tokio::main]
-async fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Please enter a csv file with transactions");
-        return;
-    }
-    let parser = InputParser::new().unwrap();
-    let transactions = parser.parse_transactions(&args[1]).await.unwrap();
-    let mut engine = TransactionEngine::new().unwrap();
-    engine.process(&transactions);
-    engine.print_client_list();
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Process(args) => process(args).await,
+        Command::Validate(args) => validate_transactions(args).await,
+        Command::Stats(args) => stats(args).await,
+        Command::Snapshot(args) => snapshot(args).await,
+        Command::Restore(args) => restore(args),
+        Command::Serve(args) => serve(args).await,
+        Command::Generate(args) => generate(args),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            eprintln!("Error: {:#}", failure.error);
+            ExitCode::from(failure.code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, ErrorKind};
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+        let cli = Cli::try_parse_from(["kraken", "process", "a.csv", "b.csv", "--shards", "2"]);
+        match cli.unwrap().command {
+            Command::Process(args) => {
+                assert_eq!(args.input.files, vec!["a.csv", "b.csv"]);
+                assert_eq!(args.clients.format, OutputFormat::Csv);
+            }
+            command => panic!("unexpected command {:?}", command),
+        }
+        let error = |args: &[&str]| Cli::try_parse_from(args).unwrap_err().kind();
+        assert_eq!(
+            error(&["kraken", "process", "--shards", "2", "--wal", "x"]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(
+            error(&["kraken", "serve"]),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            error(&[
+                "kraken",
+                "serve",
+                "--http",
+                "127.0.0.1:1",
+                "--listen",
+                "127.0.0.1:2"
+            ]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(
+            error(&["kraken", "snapshot"]),
+            ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn test_processing_failure() {
+        let input = processing_failure(InputError(anyhow!("malformed")).into());
+        assert_eq!(input.code, EXIT_INPUT);
+        assert_eq!(processing_failure(anyhow!("books")).code, EXIT_FAILURE);
+    }
 }
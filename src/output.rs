@@ -0,0 +1,351 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+use thiserror::Error;
+
+use crate::amount::Amount;
+use crate::transaction::TransactionType;
+use crate::transaction_engine::{Client, RejectedTransaction, StoredTransaction, TransactionState};
+
+/// How reports are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Csv with a header.
+    Csv,
+    /// A single json array.
+    Json,
+    /// One json object per line.
+    JsonLines,
+    /// Aligned columns for humans.
+    Table,
+}
+
+impl OutputFormat {
+    /// Names accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["csv", "json", "json-lines", "table"];
+}
+
+/// A name `OutputFormat::from_str` doesn't know.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("unknown output format '{0}'")]
+pub struct UnknownFormat(String);
+
+impl FromStr for OutputFormat {
+    type Err = UnknownFormat;
+
+    fn from_str(name: &str) -> Result<OutputFormat, UnknownFormat> {
+        match name {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "json-lines" => Ok(OutputFormat::JsonLines),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(UnknownFormat(name.to_owned())),
+        }
+    }
+}
+
+/// A row of some report, serialized the same way by all output formats.
+pub trait Record: Serialize {
+    /// Field names in the order they are serialized, used as the header.
+    const COLUMNS: &'static [&'static str];
+}
+
+/// Balances of one client as they are reported.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ClientRecord {
+    /// Id of the client.
+    pub client: u16,
+    /// Funds that can be withdrawn.
+    pub available: Amount,
+    /// Funds held by disputes.
+    pub held: Amount,
+    /// Available and held funds.
+    pub total: Amount,
+    /// Whether a chargeback locked the account.
+    pub locked: bool,
+}
+
+impl ClientRecord {
+    /// The balances of `client` with the id `id`.
+    pub fn new(id: u16, client: &Client) -> ClientRecord {
+        ClientRecord {
+            client: id,
+            available: client.available,
+            held: client.held,
+            total: client.total,
+            locked: client.locked,
+        }
+    }
+}
+
+impl Record for ClientRecord {
+    const COLUMNS: &'static [&'static str] = &["client", "available", "held", "total", "locked"];
+}
+
+/// A transaction the engine refused, with a reason code to filter on and a
+/// message for humans.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct RejectionRecord {
+    /// Id of the transaction.
+    pub tx: u32,
+    /// Client the transaction was submitted for.
+    pub client: u16,
+    /// Type of the transaction.
+    pub r#type: TransactionType,
+    /// Amount of the transaction if it had one.
+    pub amount: Option<Amount>,
+    /// Reason code, see `TransactionError::code`.
+    pub reason: &'static str,
+    /// Why the transaction was refused, for humans.
+    pub message: String,
+}
+
+impl RejectionRecord {
+    /// The report row of `rejected`.
+    pub fn new(rejected: &RejectedTransaction) -> RejectionRecord {
+        RejectionRecord {
+            tx: rejected.transaction.tx,
+            client: rejected.transaction.client,
+            r#type: rejected.transaction.r#type.clone(),
+            amount: rejected.transaction.amount,
+            reason: rejected.error.code(),
+            message: rejected.error.to_string(),
+        }
+    }
+}
+
+impl Record for RejectionRecord {
+    const COLUMNS: &'static [&'static str] =
+        &["tx", "client", "type", "amount", "reason", "message"];
+}
+
+/// A deposit or withdrawal together with its dispute state.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct TransactionRecord {
+    /// Id of the transaction.
+    pub tx: u32,
+    /// Client the transaction belongs to.
+    pub client: u16,
+    /// Deposit or withdrawal.
+    pub r#type: TransactionType,
+    /// Amount of the transaction.
+    pub amount: Amount,
+    /// Where the transaction is in its dispute lifecycle.
+    pub state: TransactionState,
+}
+
+impl TransactionRecord {
+    /// The report row of the stored transaction `tx`.
+    pub fn new(tx: u32, stored: &StoredTransaction) -> TransactionRecord {
+        TransactionRecord {
+            tx,
+            client: stored.client,
+            r#type: stored.r#type.clone(),
+            amount: stored.amount,
+            state: stored.state,
+        }
+    }
+}
+
+impl Record for TransactionRecord {
+    const COLUMNS: &'static [&'static str] = &["tx", "client", "type", "amount", "state"];
+}
+
+/// Opens `path` for writing, or stdout if there is no path.
+pub fn open_output(path: Option<&Path>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    })
+}
+
+/// Writes `records` to `output` in `format`, csv and tables with a header.
+pub fn write_records<R: Record>(
+    records: &[R],
+    format: OutputFormat,
+    output: &mut dyn Write,
+) -> Result<()> {
+    match format {
+        OutputFormat::Csv => write_csv(records, output)?,
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut *output, records)?;
+            writeln!(output)?;
+        }
+        OutputFormat::JsonLines => {
+            for record in records {
+                serde_json::to_writer(&mut *output, record)?;
+                writeln!(output)?;
+            }
+        }
+        OutputFormat::Table => write_table(records, output)?,
+    }
+    output.flush()?;
+    Ok(())
+}
+
+fn write_csv<R: Record>(records: &[R], output: &mut dyn Write) -> Result<()> {
+    // the header is written by hand so that it is there even without any records
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(output);
+    writer.write_record(R::COLUMNS)?;
+    for record in records {
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_table<R: Record>(records: &[R], output: &mut dyn Write) -> Result<()> {
+    let mut rows = vec![R::COLUMNS.iter().map(|c| c.to_string()).collect()];
+    for record in records {
+        let value = serde_json::to_value(record)?;
+        let row: Vec<String> = R::COLUMNS
+            .iter()
+            .map(|column| match &value[column] {
+                serde_json::Value::String(s) => s.clone(),
+                serde_json::Value::Null => String::new(),
+                other => other.to_string(),
+            })
+            .collect();
+        rows.push(row);
+    }
+
+    let mut widths = vec![0; R::COLUMNS.len()];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:>width$}", cell, width = width))
+            .collect();
+        writeln!(output, "{}", cells.join("  "))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<ClientRecord> {
+        vec![
+            ClientRecord {
+                client: 1,
+                available: "1.5".parse().unwrap(),
+                held: Amount::ZERO,
+                total: "1.5".parse().unwrap(),
+                locked: false,
+            },
+            ClientRecord {
+                client: 12,
+                available: "-100".parse().unwrap(),
+                held: "2".parse().unwrap(),
+                total: "-98".parse().unwrap(),
+                locked: true,
+            },
+        ]
+    }
+
+    fn render<R: Record>(records: &[R], format: OutputFormat) -> String {
+        let mut output = Vec::new();
+        write_records(records, format, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(
+            render(&records(), OutputFormat::Csv),
+            "client,available,held,total,locked\n\
+             1,1.5000,0.0000,1.5000,false\n\
+             12,-100.0000,2.0000,-98.0000,true\n"
+        );
+        assert_eq!(
+            render::<ClientRecord>(&[], OutputFormat::Csv),
+            "client,available,held,total,locked\n"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(
+            render(&records(), OutputFormat::Json),
+            "[{\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false},\
+             {\"client\":12,\"available\":\"-100.0000\",\"held\":\"2.0000\",\"total\":\"-98.0000\",\"locked\":true}]\n"
+        );
+    }
+
+    #[test]
+    fn test_json_lines() {
+        assert_eq!(
+            render(&records(), OutputFormat::JsonLines),
+            "{\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false}\n\
+             {\"client\":12,\"available\":\"-100.0000\",\"held\":\"2.0000\",\"total\":\"-98.0000\",\"locked\":true}\n"
+        );
+    }
+
+    #[test]
+    fn test_rejections() {
+        let records = vec![
+            RejectionRecord {
+                tx: 5,
+                client: 2,
+                r#type: TransactionType::Withdrawal,
+                amount: "3".parse().ok(),
+                reason: "insufficient_funds",
+                message: "client 2 has insufficient funds".to_string(),
+            },
+            RejectionRecord {
+                tx: 9,
+                client: 1,
+                r#type: TransactionType::Dispute,
+                amount: None,
+                reason: "tx_not_found",
+                message: "transaction 9 does not exist".to_string(),
+            },
+        ];
+        assert_eq!(
+            render(&records, OutputFormat::Csv),
+            "tx,client,type,amount,reason,message\n\
+             5,2,withdrawal,3.0000,insufficient_funds,client 2 has insufficient funds\n\
+             9,1,dispute,,tx_not_found,transaction 9 does not exist\n"
+        );
+        assert_eq!(
+            render(&records[1..], OutputFormat::Json),
+            "[{\"tx\":9,\"client\":1,\"type\":\"dispute\",\"amount\":null,\
+             \"reason\":\"tx_not_found\",\"message\":\"transaction 9 does not exist\"}]\n"
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        assert_eq!(
+            render(&records(), OutputFormat::Table),
+            "client  available    held     total  locked\n\
+             \x20    1     1.5000  0.0000    1.5000   false\n\
+             \x20   12  -100.0000  2.0000  -98.0000    true\n"
+        );
+    }
+
+    #[test]
+    fn test_format_names() {
+        for name in OutputFormat::NAMES {
+            assert!(name.parse::<OutputFormat>().is_ok());
+        }
+        assert_eq!("json-lines".parse(), Ok(OutputFormat::JsonLines));
+        assert_eq!(
+            "xml".parse::<OutputFormat>().unwrap_err().to_string(),
+            "unknown output format 'xml'"
+        );
+    }
+}
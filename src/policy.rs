@@ -0,0 +1,60 @@
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::amount::Amount;
+
+#[derive(Debug, Deserialize)]
+struct OverdraftLimit {
+    client: u16,
+    limit: Amount,
+}
+
+/// Per-client settings the engine applies on top of the default rules.
+#[derive(Debug, Default, Clone)]
+pub struct Policy {
+    /// How far `available` may go below zero, clients without an entry can't overdraw.
+    pub overdraft_limits: HashMap<u16, Amount>,
+}
+
+impl Policy {
+    /// Reads overdraft limits from a csv file with the columns `client,limit`.
+    pub fn from_file(file: &str) -> Result<Policy> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(file)?;
+        let mut overdraft_limits = HashMap::new();
+        for entry in rdr.deserialize() {
+            let entry: OverdraftLimit = entry?;
+            if entry.limit < Amount::ZERO {
+                bail!(
+                    "Overdraft limit of client {} must not be negative",
+                    entry.client
+                );
+            }
+            overdraft_limits.insert(entry.client, entry.limit);
+        }
+        Ok(Policy { overdraft_limits })
+    }
+
+    /// How far the available funds of `client` may go below zero.
+    pub fn overdraft_limit(&self, client: u16) -> Amount {
+        self.overdraft_limits
+            .get(&client)
+            .copied()
+            .unwrap_or(Amount::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overdraft_limits_from_file() {
+        let policy = Policy::from_file("data/overdraft_limits.csv").unwrap();
+        assert_eq!(policy.overdraft_limit(2), "1".parse().unwrap());
+        assert_eq!(policy.overdraft_limit(7), "250.5".parse().unwrap());
+        assert_eq!(policy.overdraft_limit(1), Amount::ZERO);
+    }
+}
@@ -0,0 +1,390 @@
+use anyhow::{anyhow, bail, Result};
+use csv::StringRecord;
+use std::{
+    fs::File,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use thiserror::Error;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    task::JoinHandle,
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+
+use crate::output::ClientRecord;
+use crate::transaction::Transaction;
+use crate::transaction_engine::{ApplyError, TransactionEngine};
+
+const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+/// Longest line accepted, a transaction takes far less.
+const MAX_LINE_BYTES: usize = 4096;
+
+/// A line sent by a client.
+#[derive(Debug, PartialEq)]
+enum Request {
+    Transaction(Transaction),
+    Query(u16),
+    /// Blank lines and csv headers.
+    Nothing,
+}
+
+/// Lines are either a transaction as a json object, a csv row with the columns
+/// `type,client,tx,amount` or `query <client>`.
+fn parse_request(line: &str) -> Result<Request> {
+    let line = line.trim();
+    if line.is_empty() || line.replace(' ', "") == COLUMNS.join(",") {
+        return Ok(Request::Nothing);
+    }
+    if let Some(client) = line.strip_prefix("query") {
+        return Ok(Request::Query(client.trim().parse()?));
+    }
+    if line.starts_with('{') {
+        return Ok(Request::Transaction(Transaction::from_json(
+            serde_json::from_str(line)?,
+        )?));
+    }
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(line.as_bytes());
+    let record = match reader.records().next() {
+        Some(record) => record?,
+        None => bail!("empty record"),
+    };
+    let headers = StringRecord::from(&COLUMNS[..]);
+    Ok(Request::Transaction(record.deserialize(Some(&headers))?))
+}
+
+/// Applying or syncing a transaction failed, the engine can't be trusted anymore.
+/// Unlike a lost connection this stops the server.
+#[derive(Debug, Error)]
+#[error(transparent)]
+struct EngineFailure(anyhow::Error);
+
+/// Syncs the write-ahead log outside the engine lock. Transactions waiting for a
+/// sync share a single one, so clients aren't serialized behind the disk.
+#[derive(Debug)]
+struct GroupSync {
+    file: Arc<File>,
+    /// Transactions flushed to the log, counted under the engine lock.
+    flushed: AtomicU64,
+    /// Transactions known to be on disk.
+    synced: Mutex<u64>,
+}
+
+impl GroupSync {
+    /// Waits until the transaction flushed as `ticket` is on disk.
+    async fn sync(&self, ticket: u64) -> Result<()> {
+        let mut synced = self.synced.lock().await;
+        if *synced >= ticket {
+            return Ok(());
+        }
+        // covers everything flushed so far, not just `ticket`
+        let flushed = self.flushed.load(Ordering::SeqCst);
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || file.sync_data()).await??;
+        *synced = flushed;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    engine: Mutex<TransactionEngine>,
+    /// Only there if the engine has a write-ahead log.
+    log: Option<GroupSync>,
+}
+
+/// Executes a request, returns the line to answer with if there is any. An applied
+/// transaction is only answered once it is durable.
+async fn respond(line: &str, shared: &Shared) -> Result<Option<String>, EngineFailure> {
+    let request = match parse_request(line) {
+        Ok(request) => request,
+        Err(e) => return Ok(Some(format!("error {}", e))),
+    };
+    let mut engine = shared.engine.lock().await;
+    let transaction = match request {
+        Request::Nothing => return Ok(None),
+        Request::Query(id) => {
+            return Ok(Some(match engine.client(id) {
+                Some(client) => serde_json::to_string(&ClientRecord::new(id, client))
+                    .map_err(|e| EngineFailure(e.into()))?,
+                None => format!("error unknown client {}", id),
+            }))
+        }
+        Request::Transaction(transaction) => transaction,
+    };
+    let response = match engine.apply(&transaction) {
+        Ok(()) => "ok".to_owned(),
+        Err(ApplyError::Rejected(error)) => format!("rejected {} {}", error.code(), error),
+        Err(ApplyError::Failed(e)) => return Err(EngineFailure(e)),
+    };
+    if let Some(log) = &shared.log {
+        engine.flush().map_err(EngineFailure)?;
+        let ticket = log.flushed.fetch_add(1, Ordering::SeqCst) + 1;
+        drop(engine);
+        log.sync(ticket).await.map_err(EngineFailure)?;
+    }
+    Ok(Some(response))
+}
+
+/// Lines that are too long or not utf-8 are answered with an error before the
+/// connection is closed. Lost connections are the client's business and simply
+/// end the connection.
+async fn handle_connection(stream: TcpStream, shared: Arc<Shared>) -> Result<(), EngineFailure> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_LINE_BYTES));
+    while let Some(line) = lines.next().await {
+        let (response, close) = match line {
+            Ok(line) => match respond(&line, &shared).await? {
+                Some(response) => (response, false),
+                None => continue,
+            },
+            Err(LinesCodecError::MaxLineLengthExceeded) => (
+                format!("error line is longer than {} bytes", MAX_LINE_BYTES),
+                true,
+            ),
+            Err(LinesCodecError::Io(e)) => (format!("error {}", e), true),
+        };
+        let response = format!("{}\n", response);
+        if writer.write_all(response.as_bytes()).await.is_err() || close {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Accepts connections until `shutdown` completes and applies the transactions
+/// of all of them to `engine`. Every line is answered with `ok`, `rejected <code>
+/// <message>`, `error <message>` for lines that can't be parsed or the balances of
+/// the client as json for a query. Returns the engine once all connections are
+/// closed. Failures of the books or the write-ahead log stop the server.
+pub async fn serve(
+    listener: TcpListener,
+    engine: TransactionEngine,
+    shutdown: impl Future<Output = ()>,
+) -> Result<TransactionEngine> {
+    let log = engine.wal_file()?.map(|file| GroupSync {
+        file: Arc::new(file),
+        flushed: AtomicU64::new(0),
+        synced: Mutex::new(0),
+    });
+    let shared = Arc::new(Shared {
+        engine: Mutex::new(engine),
+        log,
+    });
+    let (failures, mut failed) = tokio::sync::mpsc::unbounded_channel();
+    let mut connections: Vec<JoinHandle<()>> = Vec::new();
+    tokio::pin!(shutdown);
+    let result = loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => break Err(e.into()),
+                };
+                let shared = shared.clone();
+                let failures = failures.clone();
+                connections.retain(|connection| !connection.is_finished());
+                connections.push(tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, shared).await {
+                        let _ = failures.send(e);
+                    }
+                }));
+            }
+            Some(EngineFailure(e)) = failed.recv() => break Err(e),
+            _ = &mut shutdown => break Ok(()),
+        }
+    };
+    for connection in &connections {
+        connection.abort();
+    }
+    for connection in connections {
+        let _ = connection.await;
+    }
+    result?;
+    let shared = Arc::try_unwrap(shared).map_err(|_| anyhow!("Connections are still open"))?;
+    Ok(shared.engine.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::tests::temp_path;
+    use crate::transaction::TransactionType;
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader, Lines, ReadHalf},
+        sync::oneshot,
+    };
+
+    struct Connection {
+        lines: Lines<BufReader<ReadHalf<TcpStream>>>,
+        writer: tokio::io::WriteHalf<TcpStream>,
+    }
+
+    impl Connection {
+        async fn open(address: std::net::SocketAddr) -> Connection {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let (reader, writer) = tokio::io::split(stream);
+            Connection {
+                lines: BufReader::new(reader).lines(),
+                writer,
+            }
+        }
+
+        async fn send(&mut self, line: &str) -> String {
+            self.send_bytes(format!("{}\n", line).as_bytes())
+                .await
+                .unwrap()
+        }
+
+        /// The answer, `None` once the server closed the connection.
+        async fn send_bytes(&mut self, bytes: &[u8]) -> Option<String> {
+            self.writer.write_all(bytes).await.unwrap();
+            self.lines.next_line().await.unwrap()
+        }
+    }
+
+    async fn start(
+        engine: TransactionEngine,
+    ) -> (
+        std::net::SocketAddr,
+        oneshot::Sender<()>,
+        JoinHandle<Result<TransactionEngine>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, engine, async {
+            let _ = stopped.await;
+        }));
+        (address, stop, server)
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 2, "1.5".parse().ok()).unwrap();
+        assert_eq!(
+            parse_request("deposit, 1, 2, 1.5").unwrap(),
+            Request::Transaction(deposit.clone())
+        );
+        assert_eq!(
+            parse_request(r#"{"type":"deposit","client":1,"tx":2,"amount":"1.5"}"#).unwrap(),
+            Request::Transaction(deposit.clone())
+        );
+        assert_eq!(
+            parse_request(r#"{"type":"deposit","client":1,"tx":2,"amount":1.5}"#).unwrap(),
+            Request::Transaction(deposit)
+        );
+        assert_eq!(
+            parse_request("dispute,1,2").unwrap(),
+            Request::Transaction(Transaction::new(TransactionType::Dispute, 1, 2, None).unwrap())
+        );
+        assert_eq!(parse_request("query 7").unwrap(), Request::Query(7));
+        assert_eq!(
+            parse_request("type, client, tx, amount").unwrap(),
+            Request::Nothing
+        );
+        assert_eq!(parse_request("  ").unwrap(), Request::Nothing);
+        assert!(parse_request("deposit,1").is_err());
+        assert!(parse_request("query x").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_serve() {
+        let (address, stop, server) = start(TransactionEngine::new().unwrap()).await;
+
+        let mut first = Connection::open(address).await;
+        let mut second = Connection::open(address).await;
+        assert_eq!(first.send("deposit,1,1,5.0").await, "ok");
+        assert_eq!(
+            second
+                .send(r#"{"type":"deposit","client":2,"tx":2,"amount":"3"}"#)
+                .await,
+            "ok"
+        );
+        assert_eq!(
+            second.send("withdrawal,2,1,1.0").await,
+            "rejected duplicate_tx transaction 1 was already processed"
+        );
+        assert_eq!(first.send("dispute,1,1,").await, "ok");
+        assert_eq!(
+            second.send("query 1").await,
+            r#"{"client":1,"available":"0.0000","held":"5.0000","total":"5.0000","locked":false}"#
+        );
+        assert_eq!(second.send("query 9").await, "error unknown client 9");
+        assert!(first.send("deposit,1,x,1").await.starts_with("error "));
+
+        stop.send(()).unwrap();
+        let engine = server.await.unwrap().unwrap();
+        assert_eq!(engine.clients.len(), 2);
+        assert_eq!(engine.rejected.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_broken_lines() {
+        let (address, stop, server) = start(TransactionEngine::new().unwrap()).await;
+
+        let mut connection = Connection::open(address).await;
+        let line = format!("{}\n", "x".repeat(MAX_LINE_BYTES + 1));
+        assert_eq!(
+            connection.send_bytes(line.as_bytes()).await.unwrap(),
+            "error line is longer than 4096 bytes"
+        );
+        assert_eq!(connection.send_bytes(b"query 1\n").await, None);
+
+        let mut connection = Connection::open(address).await;
+        assert_eq!(connection.send("deposit,1,1,1").await, "ok");
+        let answer = connection.send_bytes(b"deposit,1,2,\xff\n").await.unwrap();
+        assert!(answer.starts_with("error "), "{}", answer);
+        assert_eq!(connection.send_bytes(b"query 1\n").await, None);
+
+        stop.send(()).unwrap();
+        let engine = server.await.unwrap().unwrap();
+        assert_eq!(engine.clients.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_syncs() {
+        let path = temp_path("server.wal");
+        let _ = std::fs::remove_file(&path);
+        let engine = TransactionEngine::builder().wal(&path).build().unwrap();
+        let (address, stop, server) = start(engine).await;
+
+        let clients = (0..8u16).map(|client| {
+            tokio::spawn(async move {
+                let mut connection = Connection::open(address).await;
+                for i in 0..25 {
+                    let tx = client as u32 * 100 + i;
+                    let answer = connection
+                        .send(&format!("deposit,{},{},1", client, tx))
+                        .await;
+                    assert_eq!(answer, "ok");
+                }
+            })
+        });
+        for client in clients.collect::<Vec<_>>() {
+            client.await.unwrap();
+        }
+
+        stop.send(()).unwrap();
+        let engine = server.await.unwrap().unwrap();
+        drop(engine);
+        // every answered transaction is in the log
+        let recovered = TransactionEngine::builder().wal(&path).build().unwrap();
+        assert_eq!(recovered.clients.len(), 8);
+        assert!(recovered
+            .clients
+            .values()
+            .all(|client| client.total == "25".parse().unwrap()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,305 @@
+use anyhow::{bail, Result};
+use std::{collections::HashMap, mem, num::NonZeroUsize};
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::transaction::{Transaction, TransactionType};
+use crate::transaction_engine::{RejectedTransaction, TransactionEngine, TransactionError};
+use crate::validation::validate;
+
+const BATCH_SIZE: usize = 1024;
+const MAX_PENDING_BATCHES: usize = 16;
+
+/// A transaction together with its position in the input stream.
+type Sequenced<T> = (u64, T);
+
+type ShardResult = (TransactionEngine, Vec<Sequenced<RejectedTransaction>>);
+
+fn shard_of(client: u16, shards: usize) -> usize {
+    client as usize % shards
+}
+
+/// Applies the batches of one shard in the order they arrive.
+fn run_shard(
+    mut engine: TransactionEngine,
+    mut batches: mpsc::Receiver<Vec<Sequenced<Transaction>>>,
+) -> ShardResult {
+    let mut rejected = Vec::new();
+    while let Some(batch) = batches.blocking_recv() {
+        for (sequence, transaction) in batch {
+            if let Err(error) = engine.process_transaction(&transaction) {
+                rejected.push((sequence, RejectedTransaction { transaction, error }));
+            }
+        }
+    }
+    (engine, rejected)
+}
+
+/// Keeps track of which client every transaction id belongs to. Transaction ids are
+/// global, so the checks involving ids of other clients happen here before a
+/// transaction is handed to the shard of its client, in the same order as the
+/// engine does them.
+struct Dispatcher {
+    owners: HashMap<u32, u16>,
+}
+
+impl Dispatcher {
+    fn check(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        validate(transaction)?;
+        match transaction.r#type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                if self.owners.contains_key(&transaction.tx) {
+                    return Err(TransactionError::DuplicateTx(transaction.tx));
+                }
+                // rejected deposits and withdrawals consume their id as well
+                self.owners.insert(transaction.tx, transaction.client);
+            }
+            _ => match self.owners.get(&transaction.tx) {
+                None => return Err(TransactionError::TxNotFound(transaction.tx)),
+                Some(owner) if *owner != transaction.client => {
+                    return Err(TransactionError::WrongClient(
+                        transaction.tx,
+                        transaction.client,
+                    ))
+                }
+                Some(_) => (),
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Processes the stream like `TransactionEngine::process`, but applies the
+/// transactions of different clients in parallel. Clients are split across
+/// `shards` engines, each running on its own thread, so the order within a client
+/// is kept. Afterwards the shards are merged back into `engine`, which ends up in
+/// the same state with the same rejections in the same order as if the stream was
+/// processed sequentially.
+///
+/// The write-ahead log, the ledger and the books depend on the global order of
+/// the transactions and can't be used with sharding.
+pub async fn process(
+    engine: &mut TransactionEngine,
+    transactions: impl Stream<Item = Result<Transaction>>,
+    shards: NonZeroUsize,
+) -> Result<()> {
+    if engine.wal.is_some() || engine.ledger.is_some() || engine.books.is_some() {
+        bail!("Sharded processing doesn't support the write-ahead log, ledger or journal");
+    }
+    let shards = shards.get();
+    let mut dispatcher = Dispatcher {
+        owners: engine
+            .transactions
+            .iter()
+            .map(|(tx, stored)| (*tx, stored.client))
+            .collect(),
+    };
+
+    let mut senders = Vec::with_capacity(shards);
+    let mut workers = Vec::with_capacity(shards);
+    let mut engines: Vec<TransactionEngine> = (0..shards)
+        .map(|_| TransactionEngine::with_policy(engine.policy.clone()))
+        .collect::<Result<_>>()?;
+    for (client, state) in engine.clients.drain() {
+        engines[shard_of(client, shards)]
+            .clients
+            .insert(client, state);
+    }
+    for (tx, stored) in engine.transactions.drain() {
+        engines[shard_of(stored.client, shards)]
+            .transactions
+            .insert(tx, stored);
+    }
+    for shard in engines {
+        let (sender, receiver) = mpsc::channel(MAX_PENDING_BATCHES);
+        senders.push(sender);
+        workers.push(tokio::task::spawn_blocking(move || {
+            run_shard(shard, receiver)
+        }));
+    }
+
+    // errors are returned once the shards are merged, so that everything before
+    // them is applied just like in the sequential engine
+    let mut result = Ok(());
+    let mut rejected = Vec::new();
+    let mut batches: Vec<Vec<Sequenced<Transaction>>> = vec![Vec::new(); shards];
+    tokio::pin!(transactions);
+    let mut sequence = 0;
+    while let Some(transaction) = transactions.next().await {
+        let transaction = match transaction {
+            Ok(transaction) => transaction,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+        if let Err(error) = dispatcher.check(&transaction) {
+            rejected.push((sequence, RejectedTransaction { transaction, error }));
+        } else {
+            let shard = shard_of(transaction.client, shards);
+            batches[shard].push((sequence, transaction));
+            if batches[shard].len() == BATCH_SIZE {
+                let batch = mem::replace(&mut batches[shard], Vec::with_capacity(BATCH_SIZE));
+                if senders[shard].send(batch).await.is_err() {
+                    // the worker of the shard is gone, awaiting it below tells why
+                    break;
+                }
+            }
+        }
+        sequence += 1;
+    }
+    for (batch, sender) in batches.into_iter().zip(senders) {
+        if !batch.is_empty() {
+            let _ = sender.send(batch).await;
+        }
+    }
+
+    // the state of every shard that finished is merged back before an error is
+    // returned, only the clients of a panicked shard are lost
+    for worker in workers {
+        match worker.await {
+            Ok((shard, shard_rejected)) => {
+                engine.clients.extend(shard.clients);
+                engine.transactions.extend(shard.transactions);
+                rejected.extend(shard_rejected);
+            }
+            Err(e) => result = result.and(Err(e.into())),
+        }
+    }
+    rejected.sort_unstable_by_key(|(sequence, _)| *sequence);
+    engine
+        .rejected
+        .extend(rejected.into_iter().map(|(_, rejected)| rejected));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::input_parser::InputParser;
+    use crate::policy::Policy;
+    use crate::transaction::tests::stream;
+
+    /// Pseudo random transactions with lots of conflicts: reused ids, disputes of
+    /// other clients' transactions, overdrafts and transactions on locked accounts.
+    fn random_transactions(count: u32) -> Vec<Transaction> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move |bound: u32| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((state >> 33) % bound as u64) as u32
+        };
+        let mut owned: Vec<Vec<u32>> = vec![Vec::new(); 40];
+        (0..count)
+            .map(|i| {
+                let client = next(40) as u16;
+                // mostly refer to the client's own transactions, sometimes to any id
+                let referenced = |next: &mut dyn FnMut(u32) -> u32| {
+                    let own = &owned[client as usize];
+                    match own.is_empty() || next(5) == 0 {
+                        true => next(i + 1),
+                        false => own[next(own.len() as u32) as usize],
+                    }
+                };
+                let (r#type, tx) = match next(100) {
+                    0..=44 => (TransactionType::Deposit, i),
+                    45..=64 => (TransactionType::Withdrawal, i),
+                    65..=79 => (TransactionType::Dispute, referenced(&mut next)),
+                    80..=89 => (TransactionType::Resolve, referenced(&mut next)),
+                    90 => (TransactionType::Chargeback, referenced(&mut next)),
+                    _ => (TransactionType::Deposit, next(i + 1)),
+                };
+                if tx == i {
+                    owned[client as usize].push(tx);
+                }
+                let amount = match r#type {
+                    TransactionType::Deposit | TransactionType::Withdrawal => {
+                        format!("{}.{}", next(20), next(10000)).parse().ok()
+                    }
+                    _ => None,
+                };
+                Transaction::new(r#type, client, tx, amount).unwrap()
+            })
+            .collect()
+    }
+
+    fn assert_same(sharded: &TransactionEngine, sequential: &TransactionEngine) {
+        assert_eq!(sharded.clients, sequential.clients);
+        assert_eq!(sharded.transactions, sequential.transactions);
+        let rejected = |engine: &TransactionEngine| -> Vec<(Transaction, TransactionError)> {
+            engine
+                .rejected
+                .iter()
+                .map(|r| (r.transaction.clone(), r.error.clone()))
+                .collect()
+        };
+        assert_eq!(rejected(sharded), rejected(sequential));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_same_as_sequential() {
+        let transactions = random_transactions(20000);
+        let mut policy = Policy::default();
+        policy.overdraft_limits.insert(3, "5".parse().unwrap());
+
+        let mut sequential = TransactionEngine::with_policy(policy.clone()).unwrap();
+        sequential
+            .process(stream(transactions.clone()))
+            .await
+            .unwrap();
+        assert!(sequential.rejected.len() > 1000);
+        assert!(sequential.clients.values().any(|c| c.locked));
+
+        for shards in [1, 3, 8] {
+            let mut sharded = TransactionEngine::with_policy(policy.clone()).unwrap();
+            process(
+                &mut sharded,
+                stream(transactions.clone()),
+                NonZeroUsize::new(shards).unwrap(),
+            )
+            .await
+            .unwrap();
+            assert_same(&sharded, &sequential);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_resume_sharded() {
+        let transactions = random_transactions(5000);
+        let (first, second) = transactions.split_at(2500);
+
+        let mut sequential = TransactionEngine::new().unwrap();
+        sequential
+            .process(stream(transactions.clone()))
+            .await
+            .unwrap();
+
+        let mut sharded = TransactionEngine::new().unwrap();
+        let shards = NonZeroUsize::new(4).unwrap();
+        process(&mut sharded, stream(first.to_vec()), shards)
+            .await
+            .unwrap();
+        process(&mut sharded, stream(second.to_vec()), shards)
+            .await
+            .unwrap();
+        assert_same(&sharded, &sequential);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stream_error() {
+        let parser = InputParser::new().unwrap();
+        let transactions = parser
+            .parse_transactions("data/set_malformed.csv")
+            .await
+            .unwrap();
+        let mut engine = TransactionEngine::new().unwrap();
+        let shards = NonZeroUsize::new(2).unwrap();
+        assert!(process(&mut engine, transactions, shards).await.is_err());
+        assert_eq!(
+            engine.clients.get(&1).unwrap().total,
+            "1".parse::<Amount>().unwrap()
+        );
+    }
+}
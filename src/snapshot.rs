@@ -0,0 +1,169 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::transaction_engine::{Client, StoredTransaction, TransactionEngine};
+
+/// Bumped whenever the layout of the snapshot changes in an incompatible way.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    version: u32,
+}
+
+/// Entries are sorted by id so that the same state always gives the same file.
+#[derive(Debug, Serialize)]
+struct SnapshotRef<'a> {
+    version: u32,
+    clients: BTreeMap<u16, &'a Client>,
+    transactions: BTreeMap<u32, &'a StoredTransaction>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Snapshot {
+    clients: HashMap<u16, Client>,
+    transactions: HashMap<u32, StoredTransaction>,
+}
+
+/// Writes the clients and the transaction index including the dispute states to
+/// `path`. The file is replaced atomically, a crash never leaves half a snapshot.
+pub fn save(engine: &TransactionEngine, path: &Path) -> Result<()> {
+    let snapshot = SnapshotRef {
+        version: SNAPSHOT_VERSION,
+        clients: engine.clients.iter().map(|(id, c)| (*id, c)).collect(),
+        transactions: engine.transactions.iter().map(|(tx, t)| (*tx, t)).collect(),
+    };
+    // appended rather than replacing the extension, which may already be `tmp`
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut writer, &snapshot)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_dir(path)?;
+    Ok(())
+}
+
+/// Makes the rename durable, it is only recorded in the directory. The write-ahead
+/// log is cleared after saving, so the snapshot must not get lost in a crash.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Replaces the state of `engine` with a snapshot written by `save`. The policy
+/// and rejections aren't part of the state, they only describe a single run.
+pub fn load(path: &Path, engine: &mut TransactionEngine) -> Result<()> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Can't read snapshot {}", path.display()))?;
+    let header: Header = serde_json::from_str(&content)?;
+    if header.version != SNAPSHOT_VERSION {
+        bail!(
+            "Snapshot version {} is not supported, expected version {}",
+            header.version,
+            SNAPSHOT_VERSION
+        );
+    }
+    let snapshot: Snapshot = serde_json::from_str(&content)?;
+    engine.clients = snapshot.clients;
+    engine.transactions = snapshot.transactions;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input_parser::InputParser;
+    use crate::transaction::tests::{stream, temp_path};
+    use crate::transaction::{Transaction, TransactionType};
+    use crate::transaction_engine::TransactionState;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_save_and_load() {
+        let transactions = InputParser::new()
+            .unwrap()
+            .parse_transactions("data/set3.csv")
+            .await
+            .unwrap();
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.process(transactions).await.unwrap();
+
+        let path = temp_path("snapshot.json");
+        save(&engine, &path).unwrap();
+        let mut restored = TransactionEngine::new().unwrap();
+        load(&path, &mut restored).unwrap();
+        assert_eq!(restored.clients, engine.clients);
+        assert_eq!(restored.transactions, engine.transactions);
+        assert!(restored.rejected.is_empty());
+        fs::remove_file(&path).unwrap();
+
+        // the temporary file never replaces the snapshot itself
+        let path = temp_path("snapshot.tmp");
+        save(&engine, &path).unwrap();
+        load(&path, &mut restored).unwrap();
+        assert_eq!(restored.clients, engine.clients);
+        assert!(!temp_path("snapshot.tmp.tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_snapshot() {
+        let mut engine = TransactionEngine::new().unwrap();
+        let day1 = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 2, "3".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 2, None).unwrap(),
+        ];
+        engine.process(stream(day1)).await.unwrap();
+
+        let path = temp_path("resume.json");
+        save(&engine, &path).unwrap();
+        let mut engine = TransactionEngine::new().unwrap();
+        load(&path, &mut engine).unwrap();
+        fs::remove_file(&path).unwrap();
+        let day2 = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 1, 2, None).unwrap(),
+        ];
+        engine.process(stream(day2)).await.unwrap();
+
+        let c1 = engine.clients.get(&1).unwrap();
+        assert_eq!(c1.total, "5".parse().unwrap());
+        assert!(c1.locked);
+        assert_eq!(
+            engine.transactions.get(&2).unwrap().state,
+            TransactionState::ChargedBack
+        );
+        assert_eq!(engine.rejected.len(), 1);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let path = temp_path("snapshot_v0.json");
+        fs::write(&path, r#"{"version":0,"clients":{},"transactions":{}}"#).unwrap();
+        let mut engine = TransactionEngine::new().unwrap();
+        let error = load(&path, &mut engine).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Snapshot version 0 is not supported, expected version 1"
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::amount::{Amount, AmountError};
+use crate::output::Record;
+use crate::transaction::TransactionType;
+use crate::transaction_engine::{TransactionEngine, TransactionState};
+
+/// Transactions per type, e.g. of an input stream.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TypeCounts {
+    /// Number of deposits.
+    pub deposit: u64,
+    /// Number of withdrawals.
+    pub withdrawal: u64,
+    /// Number of disputes.
+    pub dispute: u64,
+    /// Number of resolves.
+    pub resolve: u64,
+    /// Number of chargebacks.
+    pub chargeback: u64,
+}
+
+impl TypeCounts {
+    /// Counts a transaction of type `r#type`.
+    pub fn add(&mut self, r#type: &TransactionType) {
+        let count = match r#type {
+            TransactionType::Deposit => &mut self.deposit,
+            TransactionType::Withdrawal => &mut self.withdrawal,
+            TransactionType::Dispute => &mut self.dispute,
+            TransactionType::Resolve => &mut self.resolve,
+            TransactionType::Chargeback => &mut self.chargeback,
+        };
+        *count += 1;
+    }
+
+    /// Transactions of all types.
+    pub fn total(&self) -> u64 {
+        self.deposit + self.withdrawal + self.dispute + self.resolve + self.chargeback
+    }
+}
+
+/// Summary of the state of an engine, see `TransactionEngine::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Rejections per reason code.
+    pub rejected: BTreeMap<&'static str, u64>,
+    /// Number of clients.
+    pub clients: usize,
+    /// Clients whose account is locked.
+    pub locked_clients: usize,
+    /// Available funds of all clients.
+    pub available: Amount,
+    /// Held funds of all clients.
+    pub held: Amount,
+    /// Total funds of all clients.
+    pub total: Amount,
+    /// Deposits and withdrawals currently in dispute.
+    pub disputed: usize,
+    /// Deposits and withdrawals that were charged back.
+    pub charged_back: usize,
+}
+
+/// One figure of the statistics as it is reported.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct StatRecord {
+    /// Name of the figure.
+    pub stat: String,
+    /// The figure, formatted.
+    pub value: String,
+}
+
+impl Record for StatRecord {
+    const COLUMNS: &'static [&'static str] = &["stat", "value"];
+}
+
+impl Stats {
+    /// The statistics as records, preceded by the transactions per type in `counts`.
+    pub fn records(&self, counts: &TypeCounts) -> Vec<StatRecord> {
+        let mut records = Vec::new();
+        let mut stat = |stat: &str, value: String| {
+            records.push(StatRecord {
+                stat: stat.to_owned(),
+                value,
+            })
+        };
+        stat("transactions", counts.total().to_string());
+        stat("deposit", counts.deposit.to_string());
+        stat("withdrawal", counts.withdrawal.to_string());
+        stat("dispute", counts.dispute.to_string());
+        stat("resolve", counts.resolve.to_string());
+        stat("chargeback", counts.chargeback.to_string());
+        stat("rejected", self.rejected.values().sum::<u64>().to_string());
+        for (reason, count) in &self.rejected {
+            stat(&format!("rejected:{}", reason), count.to_string());
+        }
+        stat("clients", self.clients.to_string());
+        stat("locked_clients", self.locked_clients.to_string());
+        stat("available", self.available.to_string());
+        stat("held", self.held.to_string());
+        stat("total", self.total.to_string());
+        stat("disputed", self.disputed.to_string());
+        stat("charged_back", self.charged_back.to_string());
+        records
+    }
+}
+
+pub(crate) fn collect(engine: &TransactionEngine) -> Result<Stats, AmountError> {
+    let mut rejected = BTreeMap::new();
+    for rejection in &engine.rejected {
+        *rejected.entry(rejection.error.code()).or_default() += 1;
+    }
+    let (mut available, mut held, mut total) = (Amount::ZERO, Amount::ZERO, Amount::ZERO);
+    for client in engine.clients.values() {
+        available = available.checked_add(client.available)?;
+        held = held.checked_add(client.held)?;
+        total = total.checked_add(client.total)?;
+    }
+    let in_state = |state| {
+        engine
+            .transactions
+            .values()
+            .filter(|transaction| transaction.state == state)
+            .count()
+    };
+    Ok(Stats {
+        rejected,
+        clients: engine.clients.len(),
+        locked_clients: engine.clients.values().filter(|c| c.locked).count(),
+        available,
+        held,
+        total,
+        disputed: in_state(TransactionState::Disputed),
+        charged_back: in_state(TransactionState::ChargedBack),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input_parser::InputParser;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stats() {
+        let transactions = InputParser::new()
+            .unwrap()
+            .parse_transactions("data/set2.csv")
+            .await
+            .unwrap();
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.process(transactions).await.unwrap();
+        let stats = engine.stats().unwrap();
+        assert_eq!(stats.clients, 1);
+        assert_eq!(stats.locked_clients, 0);
+        assert_eq!(stats.rejected, BTreeMap::from([("dispute_settled", 1)]));
+        assert_eq!(
+            stats.total,
+            stats.available.checked_add(stats.held).unwrap()
+        );
+
+        let mut counts = TypeCounts::default();
+        counts.add(&TransactionType::Deposit);
+        counts.add(&TransactionType::Dispute);
+        let records = stats.records(&counts);
+        assert_eq!(
+            records[..3],
+            [
+                StatRecord {
+                    stat: "transactions".to_owned(),
+                    value: "2".to_owned()
+                },
+                StatRecord {
+                    stat: "deposit".to_owned(),
+                    value: "1".to_owned()
+                },
+                StatRecord {
+                    stat: "withdrawal".to_owned(),
+                    value: "0".to_owned()
+                },
+            ]
+        );
+        let last = records.last().unwrap();
+        assert_eq!(
+            (last.stat.as_str(), last.value.as_str()),
+            ("charged_back", "0")
+        );
+    }
+}
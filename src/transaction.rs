@@ -1,35 +1,92 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
 
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+use crate::amount::Amount;
+
+/// What a transaction does to the account of its client.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum TransactionType {
+    /// Reverses a disputed transaction and locks the account.
     Chargeback,
+    /// Credits the account.
     Deposit,
+    /// Holds the funds of an earlier transaction.
     Dispute,
+    /// Releases the funds of a disputed transaction.
     Resolve,
+    /// Debits the account.
     Withdrawal,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Deposit => "deposit",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Withdrawal => "withdrawal",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A row of the input.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Transaction {
+    /// What the transaction does.
     pub r#type: TransactionType,
+    /// Client whose account it applies to.
     pub client: u16,
+    /// Id of a deposit or withdrawal, or the id a dispute step refers to.
     pub tx: u32,
-    pub amount: Option<f32>,
+    /// Amount of a deposit or withdrawal, none for dispute steps.
+    pub amount: Option<Amount>,
+}
+
+impl Transaction {
+    /// Reads a transaction from json, the amount may be a string or a number. A
+    /// number is taken by its shortest decimal representation, so `1.5` is exactly
+    /// the same amount as `"1.5"`.
+    pub fn from_json(mut value: Value) -> serde_json::Result<Transaction> {
+        if let Some(amount) = value.get_mut("amount") {
+            if let Value::Number(number) = amount {
+                *amount = Value::String(number.to_string());
+            }
+        }
+        serde_json::from_value(value)
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use anyhow::Result;
-    use std::{fs::File, io::Read};
+    use serde_json::json;
+    use std::{fs::File, io::Read, path::PathBuf};
+    use tokio_stream::Stream;
+
+    pub(crate) fn stream(
+        transactions: Vec<Transaction>,
+    ) -> impl Stream<Item = Result<Transaction>> {
+        tokio_stream::iter(transactions.into_iter().map(Ok))
+    }
+
+    /// A file in the temp dir unique to the test and the test run, tests remove it
+    /// once they are done.
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("kraken_test_{}_{}", std::process::id(), name))
+    }
 
     impl Transaction {
+        /// Shorthand for building a transaction in tests.
         pub fn new(
             r#type: TransactionType,
             client: u16,
             tx: u32,
-            amount: Option<f32>,
+            amount: Option<Amount>,
         ) -> Result<Transaction> {
             Ok(Transaction {
                 r#type,
@@ -40,7 +97,7 @@ mod tests {
         }
     }
 
-    fn do_vecs_match<T: PartialEq>(a: &Vec<T>, b: &Vec<T>) -> bool {
+    fn do_vecs_match<T: PartialEq>(a: &[T], b: &[T]) -> bool {
         //TODO: What happens with NaN?
         let matching = a.iter().zip(b.iter()).filter(|&(a, b)| a == b).count();
         matching == a.len() && matching == b.len()
@@ -57,8 +114,8 @@ mod tests {
             output.push(transaction);
         }
 
-        let mut expected_output = Vec::<Transaction>::new();
-        expected_output.push(Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0)).unwrap());
+        let expected_output =
+            vec![Transaction::new(TransactionType::Deposit, 1, 1, "1.0".parse().ok()).unwrap()];
         assert!(do_vecs_match(&output, &expected_output));
     }
 
@@ -76,14 +133,13 @@ mod tests {
             output.push(transaction);
         }
 
-        let mut expected_output = Vec::<Transaction>::new();
-        expected_output.push(Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0)).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Deposit, 2, 2, Some(2.0)).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Deposit, 1, 3, Some(2.0)).unwrap());
-        expected_output
-            .push(Transaction::new(TransactionType::Withdrawal, 1, 4, Some(1.5)).unwrap());
-        expected_output
-            .push(Transaction::new(TransactionType::Withdrawal, 2, 5, Some(3.0)).unwrap());
+        let expected_output = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "1.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 2, 2, "2.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 3, "2.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 4, "1.5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 2, 5, "3.0".parse().ok()).unwrap(),
+        ];
         assert!(do_vecs_match(&output, &expected_output));
     }
 
@@ -101,13 +157,13 @@ mod tests {
             output.push(transaction);
         }
 
-        let mut expected_output = Vec::<Transaction>::new();
-        expected_output.push(Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0)).unwrap());
-        expected_output
-            .push(Transaction::new(TransactionType::Withdrawal, 1, 2, Some(1.0)).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Resolve, 1, 1, None).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Chargeback, 1, 1, None).unwrap());
+        let expected_output = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "1.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, "1.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Resolve, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None).unwrap(),
+        ];
         assert!(do_vecs_match(&output, &expected_output));
     }
 
@@ -116,9 +172,10 @@ mod tests {
         let mut file = File::open("data/set_whitespace.csv").unwrap();
         let mut input = String::new();
         file.read_to_string(&mut input).unwrap();
-        input.retain(|c| c != ' ');
 
-        let mut rdr = csv::Reader::from_reader(input.as_bytes());
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
 
         let mut output = Vec::<Transaction>::new();
         for result in rdr.deserialize() {
@@ -126,13 +183,39 @@ mod tests {
             output.push(transaction);
         }
 
-        let mut expected_output = Vec::<Transaction>::new();
-        expected_output.push(Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0)).unwrap());
-        expected_output
-            .push(Transaction::new(TransactionType::Withdrawal, 1, 2, Some(1.0)).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Resolve, 1, 1, None).unwrap());
-        expected_output.push(Transaction::new(TransactionType::Chargeback, 1, 1, None).unwrap());
+        let expected_output = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "1.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, "1.0".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Resolve, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None).unwrap(),
+        ];
         assert!(do_vecs_match(&output, &expected_output));
     }
+
+    #[test]
+    fn test_from_json() {
+        let deposit = |amount: &str| {
+            Transaction::new(TransactionType::Deposit, 1, 2, amount.parse().ok()).unwrap()
+        };
+        let json = |amount: Value| {
+            Transaction::from_json(
+                json!({"type": "deposit", "client": 1, "tx": 2, "amount": amount}),
+            )
+        };
+        assert_eq!(json(json!("1.5")).unwrap(), deposit("1.5"));
+        assert_eq!(json(json!(1.5)).unwrap(), deposit("1.5"));
+        assert_eq!(json(json!(0.1)).unwrap(), deposit("0.1"));
+        assert_eq!(json(json!(12)).unwrap(), deposit("12"));
+        assert_eq!(json(json!(-3)).unwrap(), deposit("-3"));
+        assert_eq!(
+            json(json!(1.00001)).unwrap_err().to_string(),
+            "amount '1.00001' has more than 4 decimal places"
+        );
+        let dispute = Transaction::from_json(json!({"type": "dispute", "client": 1, "tx": 2}));
+        assert_eq!(
+            dispute.unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 2, None).unwrap()
+        );
+    }
 }
@@ -1,238 +1,809 @@
 use anyhow::Result;
 use core::fmt;
-use std::{cmp::Ordering, collections::HashMap};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use thiserror::Error;
+use tokio_stream::{Stream, StreamExt};
 
+use crate::amount::{Amount, AmountError};
+use crate::bookkeeping::{BookkeepingError, Books};
+use crate::ledger::Ledger;
+use crate::policy::Policy;
+use crate::snapshot;
+use crate::stats::{self, Stats};
 use crate::transaction::{Transaction, TransactionType};
+use crate::validation::{validate, ValidationError};
+pub use crate::wal::Recovery;
+use crate::wal::{self, WriteAheadLog};
 
+/// Why the engine refused a transaction.
+#[derive(Debug, Error, PartialEq, Eq, Clone)]
+pub enum TransactionError {
+    /// Anything but a deposit for a client without an account.
+    #[error("client {0} does not exist")]
+    UnknownClient(u16),
+    /// The account was locked by a chargeback.
+    #[error("account of client {0} is locked")]
+    AccountLocked(u16),
+    /// A withdrawal beyond the available funds and overdraft limit.
+    #[error("client {0} has insufficient funds")]
+    InsufficientFunds(u16),
+    /// A dispute step refers to an unknown transaction.
+    #[error("transaction {0} does not exist")]
+    TxNotFound(u32),
+    /// A dispute step refers to a transaction of another client.
+    #[error("transaction {0} does not belong to client {1}")]
+    WrongClient(u32, u16),
+    /// A deposit or withdrawal reuses an id.
+    #[error("transaction {0} was already processed")]
+    DuplicateTx(u32),
+    /// A dispute step refers to a rejected transaction.
+    #[error("transaction {0} was rejected and can't be disputed")]
+    TxRejected(u32),
+    /// A dispute of a transaction that is disputed already.
+    #[error("transaction {0} is already disputed")]
+    AlreadyDisputed(u32),
+    /// A resolve or chargeback of a transaction that isn't disputed.
+    #[error("transaction {0} is not disputed")]
+    NotDisputed(u32),
+    /// A dispute step of a transaction that was resolved or charged back.
+    #[error("dispute of transaction {0} is already settled, it was {1}")]
+    DisputeSettled(u32, TransactionState),
+    /// A dispute step refers to something other than a deposit or withdrawal.
+    #[error("transaction {0} is a {1} and can't be disputed")]
+    NotDisputable(u32, TransactionType),
+    /// The transaction breaks a rule on its own.
+    #[error(transparent)]
+    Invalid(#[from] ValidationError),
+    /// A balance doesn't fit into an amount.
+    #[error(transparent)]
+    Amount(#[from] AmountError),
+    /// The books refused the transaction.
+    #[error(transparent)]
+    Books(#[from] BookkeepingError),
+}
+
+impl TransactionError {
+    /// Stable reason code of a rejection, unlike the message it doesn't carry any ids.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransactionError::UnknownClient(_) => "unknown_client",
+            TransactionError::AccountLocked(_) => "account_locked",
+            TransactionError::InsufficientFunds(_) => "insufficient_funds",
+            TransactionError::TxNotFound(_) => "tx_not_found",
+            TransactionError::WrongClient(..) => "wrong_client",
+            TransactionError::DuplicateTx(_) => "duplicate_tx",
+            TransactionError::TxRejected(_) => "tx_rejected",
+            TransactionError::AlreadyDisputed(_) => "already_disputed",
+            TransactionError::NotDisputed(_) => "not_disputed",
+            TransactionError::DisputeSettled(..) => "dispute_settled",
+            TransactionError::NotDisputable(..) => "not_disputable",
+            TransactionError::Invalid(error) => error.code(),
+            TransactionError::Amount(_) => "amount_overflow",
+            TransactionError::Books(error) => error.code(),
+        }
+    }
+}
+
+/// Why `TransactionEngine::apply` didn't go through.
+#[derive(Debug, Error)]
+pub enum ApplyError {
+    /// The engine refused the transaction, it had no effect on any account.
+    #[error(transparent)]
+    Rejected(#[from] TransactionError),
+    /// The books or the write-ahead log failed, the engine shouldn't be used anymore.
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+/// A transaction that had no effect on any account, together with the reason.
 #[derive(Debug)]
+pub struct RejectedTransaction {
+    /// The transaction as it was submitted.
+    pub transaction: Transaction,
+    /// Why it was refused.
+    pub error: TransactionError,
+}
+
+/// Order of the client list, balances are sorted from largest to smallest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientOrder {
+    /// By client id.
+    Id,
+    /// By total funds.
+    Total,
+    /// By available funds.
+    Available,
+}
+
+impl ClientOrder {
+    /// Names accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["id", "total", "available"];
+}
+
+/// A name `ClientOrder::from_str` doesn't know.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("unknown client order '{0}'")]
+pub struct UnknownOrder(String);
+
+impl FromStr for ClientOrder {
+    type Err = UnknownOrder;
+
+    fn from_str(name: &str) -> Result<ClientOrder, UnknownOrder> {
+        match name {
+            "id" => Ok(ClientOrder::Id),
+            "total" => Ok(ClientOrder::Total),
+            "available" => Ok(ClientOrder::Available),
+            _ => Err(UnknownOrder(name.to_owned())),
+        }
+    }
+}
+
+/// Balances of a client, `total` is always `available + held`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Client {
-    available: f32,
-    held: f32,
-    total: f32,
-    locked: bool,
+    pub(crate) available: Amount,
+    pub(crate) held: Amount,
+    pub(crate) total: Amount,
+    pub(crate) locked: bool,
+}
+
+impl Client {
+    /// Funds that can be withdrawn.
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+
+    /// Funds of disputed transactions.
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    /// All funds of the client, `available + held`.
+    pub fn total(&self) -> Amount {
+        self.total
+    }
+
+    /// Locked accounts had a chargeback and refuse deposits and withdrawals.
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
 }
 
-impl fmt::Display for Client {
+/// Lifecycle of a stored transaction: `Processed -> Disputed -> Resolved | ChargedBack`.
+/// Resolved and charged back transactions can't be disputed again. Rejected deposits
+/// and withdrawals are stored as well, so that their id can't be reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionState {
+    /// The deposit or withdrawal was refused, it only reserves its id.
+    Rejected,
+    /// Applied and not disputed.
+    Processed,
+    /// Its funds are held.
+    Disputed,
+    /// The dispute released the funds again.
+    Resolved,
+    /// The dispute reversed the transaction.
+    ChargedBack,
+}
+
+impl TransactionState {
+    /// Returns the state `action` (a dispute, resolve or chargeback) moves the
+    /// transaction `tx` into, or why that move isn't allowed.
+    pub(crate) fn transition(
+        self,
+        tx: u32,
+        action: &TransactionType,
+    ) -> Result<TransactionState, TransactionError> {
+        match (self, action) {
+            (TransactionState::Processed, TransactionType::Dispute) => {
+                Ok(TransactionState::Disputed)
+            }
+            (TransactionState::Disputed, TransactionType::Resolve) => {
+                Ok(TransactionState::Resolved)
+            }
+            (TransactionState::Disputed, TransactionType::Chargeback) => {
+                Ok(TransactionState::ChargedBack)
+            }
+            (TransactionState::Disputed, _) => Err(TransactionError::AlreadyDisputed(tx)),
+            (TransactionState::Processed, _) => Err(TransactionError::NotDisputed(tx)),
+            (TransactionState::Rejected, _) => Err(TransactionError::TxRejected(tx)),
+            (settled, _) => Err(TransactionError::DisputeSettled(tx, settled)),
+        }
+    }
+}
+
+impl fmt::Display for TransactionState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{},{},{},{}",
-            self.available, self.held, self.total, self.locked
-        )
+        let state = match self {
+            TransactionState::Rejected => "rejected",
+            TransactionState::Processed => "processed",
+            TransactionState::Disputed => "disputed",
+            TransactionState::Resolved => "resolved",
+            TransactionState::ChargedBack => "charged back",
+        };
+        write!(f, "{}", state)
     }
 }
 
+/// A deposit or withdrawal kept around so that it can be disputed later on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredTransaction {
+    /// Client the transaction belongs to.
+    pub client: u16,
+    /// Deposit or withdrawal.
+    pub r#type: TransactionType,
+    /// Amount of the transaction.
+    pub amount: Amount,
+    /// Where the transaction is in its dispute lifecycle.
+    pub state: TransactionState,
+}
+
+/// Applies transactions to the accounts of clients, one at a time and in order.
+///
+/// Construct it with `TransactionEngine::builder()` to restore a snapshot or enable
+/// the write-ahead log, the ledger or the books.
 #[derive(Debug)]
 pub struct TransactionEngine {
-    pub clients: HashMap<u16, Client>,
-    pub dispute_transactions: Vec<Transaction>,
+    pub(crate) clients: HashMap<u16, Client>,
+    pub(crate) transactions: HashMap<u32, StoredTransaction>,
+    pub(crate) rejected: Vec<RejectedTransaction>,
+    pub(crate) policy: Policy,
+    /// Accepted transactions are appended to this log if there is one.
+    pub(crate) wal: Option<WriteAheadLog>,
+    /// What replaying the log on startup found.
+    pub(crate) recovery: Recovery,
+    /// Every balance change is recorded in this ledger if there is one.
+    pub(crate) ledger: Option<Ledger>,
+    /// Applied transactions are posted to these double-entry books if there are any,
+    /// books that don't add up stop the processing.
+    pub(crate) books: Option<Books>,
+}
+
+/// Sets up a `TransactionEngine`, by default it starts empty without any of the
+/// optional records.
+#[derive(Debug, Default)]
+pub struct TransactionEngineBuilder {
+    policy: Policy,
+    snapshot: Option<PathBuf>,
+    wal: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    ledger: bool,
+    journal: bool,
+}
+
+impl TransactionEngineBuilder {
+    /// Limits applied to the clients, the default policy if not set.
+    pub fn policy(mut self, policy: Policy) -> TransactionEngineBuilder {
+        self.policy = policy;
+        self
+    }
+
+    /// Starts from the state in this snapshot instead of starting empty.
+    pub fn snapshot(mut self, path: impl Into<PathBuf>) -> TransactionEngineBuilder {
+        self.snapshot = Some(path.into());
+        self
+    }
+
+    /// Replays this write-ahead log on top of the snapshot and appends accepted
+    /// transactions to it.
+    pub fn wal(mut self, path: impl Into<PathBuf>) -> TransactionEngineBuilder {
+        self.wal = Some(path.into());
+        self
+    }
+
+    /// Replays this write-ahead log on top of the snapshot without writing to it,
+    /// the engine doesn't log anything afterwards.
+    pub fn replay(mut self, path: impl Into<PathBuf>) -> TransactionEngineBuilder {
+        self.replay = Some(path.into());
+        self
+    }
+
+    /// Records every balance change in a ledger.
+    pub fn ledger(mut self, enabled: bool) -> TransactionEngineBuilder {
+        self.ledger = enabled;
+        self
+    }
+
+    /// Posts applied transactions to double-entry books, opened with the balances
+    /// after recovery.
+    pub fn journal(mut self, enabled: bool) -> TransactionEngineBuilder {
+        self.journal = enabled;
+        self
+    }
+
+    /// Creates the engine, restoring the snapshot and recovering or replaying the
+    /// log in that order. Fails if any of the files can't be read.
+    pub fn build(self) -> Result<TransactionEngine> {
+        let mut engine = TransactionEngine::with_policy(self.policy)?;
+        if let Some(path) = self.snapshot {
+            snapshot::load(&path, &mut engine)?;
+        }
+        if self.ledger {
+            engine.ledger = Some(Ledger::default());
+        }
+        if let Some(path) = self.wal {
+            let (wal, recovery) = wal::recover(&path, &mut engine)?;
+            engine.wal = Some(wal);
+            engine.recovery = recovery;
+        }
+        if let Some(path) = self.replay {
+            engine.recovery = wal::replay(&path, &mut engine)?;
+        }
+        if self.journal {
+            engine.books = Some(Books::new(&engine.clients)?);
+        }
+        Ok(engine)
+    }
 }
 
 impl TransactionEngine {
+    /// Builds an engine with a snapshot, a write-ahead log, a ledger or books.
+    pub fn builder() -> TransactionEngineBuilder {
+        TransactionEngineBuilder::default()
+    }
+
+    /// An empty engine with the default policy and no log, ledger or books.
     pub fn new() -> Result<TransactionEngine> {
+        TransactionEngine::with_policy(Policy::default())
+    }
+
+    /// An empty engine applying `policy`, otherwise like `new`.
+    pub fn with_policy(policy: Policy) -> Result<TransactionEngine> {
         Ok(TransactionEngine {
             clients: HashMap::new(),
-            dispute_transactions: Vec::new(),
+            transactions: HashMap::new(),
+            rejected: Vec::new(),
+            policy,
+            wal: None,
+            recovery: Recovery::default(),
+            ledger: None,
+            books: None,
         })
     }
 
-    pub fn process(&mut self, transactions: &[Transaction]) {
-        for transaction in transactions {
-            match transaction.r#type {
-                TransactionType::Chargeback => handle_chargeback(
-                    transaction,
-                    &mut self.clients,
-                    &mut self.dispute_transactions,
-                ),
-                TransactionType::Deposit => handle_deposit(transaction, &mut self.clients),
-                TransactionType::Dispute => handle_dispute(
-                    transaction,
-                    &mut self.clients,
-                    transactions,
-                    &mut self.dispute_transactions,
-                ),
-                TransactionType::Resolve => handle_resolve(
-                    transaction,
-                    &mut self.clients,
-                    &mut self.dispute_transactions,
-                ),
-                TransactionType::Withdrawal => handle_withdrawal(transaction, &mut self.clients),
+    /// Consumes the stream one transaction at a time. Transactions the engine
+    /// refuses are collected in `rejected`, only errors of the stream itself stop
+    /// the processing.
+    pub async fn process(
+        &mut self,
+        transactions: impl Stream<Item = Result<Transaction>>,
+    ) -> Result<()> {
+        tokio::pin!(transactions);
+        while let Some(transaction) = transactions.next().await {
+            match self.apply(&transaction?) {
+                Ok(()) | Err(ApplyError::Rejected(_)) => (),
+                Err(ApplyError::Failed(e)) => return Err(e),
             }
         }
+        self.sync()
     }
 
-    pub fn print_client_list(&self) {
-        println!("client,available,held,total,locked");
-        for (id, client) in self.clients.iter() {
-            println!("{},{}", id, client);
+    /// Applies a single transaction, posts it to the books and appends it to the
+    /// write-ahead log without syncing it. A refused transaction is added to the
+    /// rejections as well, and logged if it consumed its id.
+    pub fn apply(&mut self, transaction: &Transaction) -> Result<(), ApplyError> {
+        let known = self.transactions.len();
+        if let Err(error) = self.process_transaction(transaction) {
+            self.rejected.push(RejectedTransaction {
+                transaction: transaction.clone(),
+                error: error.clone(),
+            });
+            // replaying it rejects it again and takes its id the same way
+            if self.transactions.len() > known {
+                if let Some(wal) = self.wal.as_mut() {
+                    wal.append(transaction, Some(&error))?;
+                }
+            }
+            return Err(error.into());
+        }
+        if let Some(books) = self.books.as_mut() {
+            books
+                .post(transaction, &self.transactions, &self.clients)
+                .map_err(|e| ApplyError::Failed(e.into()))?;
         }
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append(transaction, None)?;
+        }
+        Ok(())
     }
-}
 
-fn handle_deposit(transaction: &Transaction, clients: &mut HashMap<u16, Client>) {
-    if let Some(client) = clients.get_mut(&transaction.client) {
-        if !client.locked {
-            client.available += transaction.amount.unwrap();
-            client.total += transaction.amount.unwrap();
+    /// Makes the transactions appended to the write-ahead log durable.
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the transactions appended to the write-ahead log to its file without
+    /// waiting for the disk, syncing the file of `wal_file` makes them durable.
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.flush()?;
+        }
+        Ok(())
+    }
+
+    /// A handle to the file of the write-ahead log, to sync it without holding the
+    /// engine. `None` without a log.
+    pub fn wal_file(&self) -> Result<Option<File>> {
+        self.wal
+            .as_ref()
+            .map(WriteAheadLog::try_clone_file)
+            .transpose()
+    }
+
+    /// Saves the state to a snapshot and clears the write-ahead log, which only has
+    /// to hold what happened since the last snapshot.
+    pub fn save_snapshot(&mut self, path: &Path) -> Result<()> {
+        snapshot::save(self, path)?;
+        if let Some(wal) = self.wal.as_mut() {
+            wal.truncate()?;
+        }
+        Ok(())
+    }
+
+    /// Balances of the client `id`, if there is an account.
+    pub fn client(&self, id: u16) -> Option<&Client> {
+        self.clients.get(&id)
+    }
+
+    /// Balances of all clients by id, in no particular order.
+    pub fn clients(&self) -> &HashMap<u16, Client> {
+        &self.clients
+    }
+
+    /// A deposit or withdrawal the engine has seen, including its dispute state.
+    pub fn transaction(&self, tx: u32) -> Option<&StoredTransaction> {
+        self.transactions.get(&tx)
+    }
+
+    /// All deposits and withdrawals by id, rejected ones included.
+    pub fn transactions(&self) -> &HashMap<u32, StoredTransaction> {
+        &self.transactions
+    }
+
+    /// Refused transactions in the order they were submitted.
+    pub fn rejected(&self) -> &[RejectedTransaction] {
+        &self.rejected
+    }
+
+    /// Limits the engine applies to the clients.
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// The audit ledger if the engine keeps one.
+    pub fn ledger(&self) -> Option<&Ledger> {
+        self.ledger.as_ref()
+    }
+
+    /// The double-entry books if the engine keeps them.
+    pub fn books(&self) -> Option<&Books> {
+        self.books.as_ref()
+    }
+
+    /// What replaying the write-ahead log found when the engine was built, nothing
+    /// without a log.
+    pub fn recovery(&self) -> Recovery {
+        self.recovery
+    }
+
+    /// Rejections per reason, the number of (locked) clients, the sums of their
+    /// balances and the disputed and charged back transactions.
+    pub fn stats(&self) -> Result<Stats, AmountError> {
+        stats::collect(self)
+    }
+
+    pub(crate) fn process_transaction(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        let before = match self.ledger {
+            Some(_) => self.clients.get(&transaction.client).cloned(),
+            None => None,
+        };
+        self.apply_transaction(transaction)?;
+        if let Some(ledger) = self.ledger.as_mut() {
+            let amount = match transaction.amount {
+                Some(amount) => amount,
+                None => self.transactions[&transaction.tx].amount,
+            };
+            ledger.record(
+                transaction,
+                amount,
+                &before.unwrap_or_default(),
+                &self.clients[&transaction.client],
+            );
+        }
+        Ok(())
+    }
+
+    fn apply_transaction(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        validate(transaction)?;
+        if let Some(books) = self.books.as_ref() {
+            books.check(transaction, &self.transactions)?;
+        }
+        match transaction.r#type {
+            TransactionType::Chargeback => {
+                handle_chargeback(transaction, &mut self.clients, &mut self.transactions)
+            }
+            TransactionType::Deposit => {
+                check_unique(transaction, &self.transactions)?;
+                let amount = amount_of(transaction)?;
+                let result = handle_deposit(transaction, amount, &mut self.clients);
+                index_transaction(transaction, amount, &mut self.transactions, result.is_ok());
+                result
+            }
+            TransactionType::Dispute => {
+                handle_dispute(transaction, &mut self.clients, &mut self.transactions)
+            }
+            TransactionType::Resolve => {
+                handle_resolve(transaction, &mut self.clients, &mut self.transactions)
+            }
+            TransactionType::Withdrawal => {
+                check_unique(transaction, &self.transactions)?;
+                let amount = amount_of(transaction)?;
+                let overdraft_limit = self.policy.overdraft_limit(transaction.client);
+                let result =
+                    handle_withdrawal(transaction, amount, &mut self.clients, overdraft_limit);
+                index_transaction(transaction, amount, &mut self.transactions, result.is_ok());
+                result
+            }
         }
-    } else {
-        clients.insert(
-            transaction.client,
-            Client {
-                available: transaction.amount.unwrap(),
-                held: 0f32,
-                total: transaction.amount.unwrap(),
-                locked: false,
-            },
-        );
     }
-}
 
-fn handle_withdrawal(transaction: &Transaction, clients: &mut HashMap<u16, Client>) {
-    if let Some(client) = clients.get_mut(&transaction.client) {
-        if !client.locked {
-            client.available -= transaction.amount.unwrap();
-            client.total -= transaction.amount.unwrap();
+    /// Returns the clients in a deterministic order, ties are broken by client id.
+    pub fn sorted_clients(&self, order: ClientOrder) -> Vec<(u16, &Client)> {
+        let mut clients: Vec<(u16, &Client)> = self
+            .clients
+            .iter()
+            .map(|(id, client)| (*id, client))
+            .collect();
+        clients.sort_unstable_by_key(|(id, _)| *id);
+        match order {
+            ClientOrder::Id => (),
+            ClientOrder::Total => clients.sort_by_key(|(_, c)| Reverse(c.total)),
+            ClientOrder::Available => clients.sort_by_key(|(_, c)| Reverse(c.available)),
         }
+        clients
+    }
+}
+
+/// Deposit and withdrawal ids are unique, replaying one is never applied twice.
+fn check_unique(
+    transaction: &Transaction,
+    transactions: &HashMap<u32, StoredTransaction>,
+) -> Result<(), TransactionError> {
+    if transactions.contains_key(&transaction.tx) {
+        return Err(TransactionError::DuplicateTx(transaction.tx));
+    }
+    Ok(())
+}
+
+/// The amount of a deposit or withdrawal. It is checked by `validate` already, but
+/// the handlers don't rely on that.
+pub(crate) fn amount_of(transaction: &Transaction) -> Result<Amount, TransactionError> {
+    transaction
+        .amount
+        .ok_or_else(|| ValidationError::MissingAmount(transaction.r#type.clone()).into())
+}
+
+fn index_transaction(
+    transaction: &Transaction,
+    amount: Amount,
+    transactions: &mut HashMap<u32, StoredTransaction>,
+    applied: bool,
+) {
+    let state = if applied {
+        TransactionState::Processed
     } else {
-        panic!("Client to withdraw money from does not exist!");
+        TransactionState::Rejected
+    };
+    transactions.insert(
+        transaction.tx,
+        StoredTransaction {
+            client: transaction.client,
+            r#type: transaction.r#type.clone(),
+            amount,
+            state,
+        },
+    );
+}
+
+/// The first deposit of a client opens its account, a rejected one leaves no
+/// account behind.
+fn handle_deposit(
+    transaction: &Transaction,
+    amount: Amount,
+    clients: &mut HashMap<u16, Client>,
+) -> Result<(), TransactionError> {
+    let (available, total) = match clients.get(&transaction.client) {
+        Some(client) if client.locked => {
+            return Err(TransactionError::AccountLocked(transaction.client));
+        }
+        Some(client) => (
+            client.available.checked_add(amount)?,
+            client.total.checked_add(amount)?,
+        ),
+        None => (amount, amount),
+    };
+    let client = clients.entry(transaction.client).or_default();
+    client.available = available;
+    client.total = total;
+    Ok(())
+}
+
+fn handle_withdrawal(
+    transaction: &Transaction,
+    amount: Amount,
+    clients: &mut HashMap<u16, Client>,
+    overdraft_limit: Amount,
+) -> Result<(), TransactionError> {
+    let client = get_client(transaction, clients)?;
+    if client.locked {
+        return Err(TransactionError::AccountLocked(transaction.client));
+    }
+    if client.available.checked_add(overdraft_limit)? < amount {
+        return Err(TransactionError::InsufficientFunds(transaction.client));
     }
+    let available = client.available.checked_sub(amount)?;
+    let total = client.total.checked_sub(amount)?;
+    client.available = available;
+    client.total = total;
+    Ok(())
 }
 
 fn handle_dispute(
     transaction: &Transaction,
     clients: &mut HashMap<u16, Client>,
-    transactions: &[Transaction],
-    dispute_transactions: &mut Vec<Transaction>,
-) {
-    if let Some(client) = clients.get_mut(&transaction.client) {
-        let transactions_in_dispute: Vec<&Transaction> = transactions
-            .iter()
-            .filter(|t| {
-                t.tx == transaction.tx
-                    && (t.r#type == TransactionType::Withdrawal
-                        || t.r#type == TransactionType::Deposit)
-            })
-            .collect();
+    transactions: &mut HashMap<u32, StoredTransaction>,
+) -> Result<(), TransactionError> {
+    let transaction_in_dispute = get_transaction(transaction, transactions)?;
+    let state = transaction_in_dispute
+        .state
+        .transition(transaction.tx, &transaction.r#type)?;
+    let client = get_client(transaction, clients)?;
 
-        match transactions_in_dispute.len().cmp(&1) {
-            Ordering::Greater => panic!("Multiple transactions found for dispute!"),
-            Ordering::Equal => {
-                let transaction_in_dispute = transactions_in_dispute[0];
-
-                if let Some(amount) = transaction_in_dispute.amount {
-                    match transaction_in_dispute.r#type {
-                        TransactionType::Deposit => {
-                            client.available -= amount;
-                            client.held += amount;
-                        }
-                        TransactionType::Withdrawal => {
-                            client.available += amount;
-                            client.held -= amount;
-                        }
-                        _ => {
-                            // technically it's nowhere written that dispute, resolve and chargeback actions
-                            // can't be in dispute themselves but I'm not sure if that's really the case
-                            unimplemented!();
-                        }
-                    }
-                }
-                dispute_transactions.push(transaction_in_dispute.to_owned());
-            }
-            _ => (), // Ignore the case that the ID does no exist
+    let amount = transaction_in_dispute.amount;
+    match transaction_in_dispute.r#type {
+        TransactionType::Deposit => {
+            let available = client.available.checked_sub(amount)?;
+            let held = client.held.checked_add(amount)?;
+            client.available = available;
+            client.held = held;
+        }
+        TransactionType::Withdrawal => {
+            let available = client.available.checked_add(amount)?;
+            let held = client.held.checked_sub(amount)?;
+            client.available = available;
+            client.held = held;
+        }
+        ref other => {
+            return Err(TransactionError::NotDisputable(
+                transaction.tx,
+                other.clone(),
+            ))
         }
-    } else {
-        panic!("Client to settle dispute for does not exist!");
     }
+    transaction_in_dispute.state = state;
+    Ok(())
 }
 
 fn handle_resolve(
     transaction: &Transaction,
     clients: &mut HashMap<u16, Client>,
-    dispute_transactions: &mut Vec<Transaction>,
-) {
-    if let Some(client) = clients.get_mut(&transaction.client) {
-        let transactions_in_dispute: Vec<&Transaction> = dispute_transactions
-            .iter()
-            .filter(|t| t.tx == transaction.tx)
-            .collect();
+    transactions: &mut HashMap<u32, StoredTransaction>,
+) -> Result<(), TransactionError> {
+    let transaction_in_dispute = get_transaction(transaction, transactions)?;
+    let state = transaction_in_dispute
+        .state
+        .transition(transaction.tx, &transaction.r#type)?;
+    let client = get_client(transaction, clients)?;
 
-        match transactions_in_dispute.len().cmp(&1) {
-            Ordering::Greater => panic!("Multiple transactions found for dispute!"),
-            Ordering::Equal => {
-                let transaction_in_dispute = transactions_in_dispute[0];
-
-                if let Some(amount) = transaction_in_dispute.amount {
-                    match transaction_in_dispute.r#type {
-                        TransactionType::Deposit => {
-                            client.available += amount;
-                            client.held -= amount;
-                        }
-                        TransactionType::Withdrawal => {
-                            client.available -= amount;
-                            client.held += amount;
-                        }
-                        _ => {
-                            unimplemented!();
-                        }
-                    }
-                } else {
-                    panic!("Transaction to resolve was not in dispute")
-                }
-            }
-            _ => (), //  Ignore the case that the ID does no exist
+    let amount = transaction_in_dispute.amount;
+    match transaction_in_dispute.r#type {
+        TransactionType::Deposit => {
+            let available = client.available.checked_add(amount)?;
+            let held = client.held.checked_sub(amount)?;
+            client.available = available;
+            client.held = held;
+        }
+        TransactionType::Withdrawal => {
+            let available = client.available.checked_sub(amount)?;
+            let held = client.held.checked_add(amount)?;
+            client.available = available;
+            client.held = held;
+        }
+        ref other => {
+            return Err(TransactionError::NotDisputable(
+                transaction.tx,
+                other.clone(),
+            ))
         }
-    } else {
-        panic!("Client to resolve transacton for does not exist!");
     }
+    transaction_in_dispute.state = state;
+    Ok(())
 }
 
 fn handle_chargeback(
     transaction: &Transaction,
     clients: &mut HashMap<u16, Client>,
-    dispute_transactions: &mut Vec<Transaction>,
-) {
-    if let Some(client) = clients.get_mut(&transaction.client) {
-        let transactions_in_dispute: Vec<&Transaction> = dispute_transactions
-            .iter()
-            .filter(|t| t.tx == transaction.tx)
-            .collect();
+    transactions: &mut HashMap<u32, StoredTransaction>,
+) -> Result<(), TransactionError> {
+    let transaction_in_dispute = get_transaction(transaction, transactions)?;
+    let state = transaction_in_dispute
+        .state
+        .transition(transaction.tx, &transaction.r#type)?;
+    let client = get_client(transaction, clients)?;
 
-        match transactions_in_dispute.len().cmp(&1) {
-            Ordering::Greater => panic!("Multiple transactions found for dispute!"),
-            Ordering::Equal => {
-                let transaction_in_dispute = transactions_in_dispute[0];
-
-                if let Some(amount) = transaction_in_dispute.amount {
-                    match transaction_in_dispute.r#type {
-                        TransactionType::Deposit => {
-                            client.held -= amount;
-                            client.total -= amount;
-                        }
-                        TransactionType::Withdrawal => {
-                            client.held += amount;
-                            client.total += amount;
-                        }
-                        _ => {
-                            unimplemented!();
-                        }
-                    }
-                } else {
-                    panic!("Transaction to resolve was not in dispute")
-                }
-                client.locked = true;
-            }
-            _ => (), //  Ignore the case that the ID does no exist
+    let amount = transaction_in_dispute.amount;
+    match transaction_in_dispute.r#type {
+        TransactionType::Deposit => {
+            let held = client.held.checked_sub(amount)?;
+            let total = client.total.checked_sub(amount)?;
+            client.held = held;
+            client.total = total;
         }
-    } else {
-        panic!("Client to resolve transacton for does not exist!");
+        TransactionType::Withdrawal => {
+            let held = client.held.checked_add(amount)?;
+            let total = client.total.checked_add(amount)?;
+            client.held = held;
+            client.total = total;
+        }
+        ref other => {
+            return Err(TransactionError::NotDisputable(
+                transaction.tx,
+                other.clone(),
+            ))
+        }
+    }
+    transaction_in_dispute.state = state;
+    client.locked = true;
+    Ok(())
+}
+
+fn get_client<'a>(
+    transaction: &Transaction,
+    clients: &'a mut HashMap<u16, Client>,
+) -> Result<&'a mut Client, TransactionError> {
+    clients
+        .get_mut(&transaction.client)
+        .ok_or(TransactionError::UnknownClient(transaction.client))
+}
+
+/// Looks up the transaction referenced by a dispute, resolve or chargeback and
+/// makes sure it belongs to the client that sent it.
+fn get_transaction<'a>(
+    transaction: &Transaction,
+    transactions: &'a mut HashMap<u32, StoredTransaction>,
+) -> Result<&'a mut StoredTransaction, TransactionError> {
+    let stored = transactions
+        .get_mut(&transaction.tx)
+        .ok_or(TransactionError::TxNotFound(transaction.tx))?;
+    if stored.client != transaction.client {
+        return Err(TransactionError::WrongClient(
+            transaction.tx,
+            transaction.client,
+        ));
     }
+    Ok(stored)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::input_parser::InputParser;
+    use crate::output::{write_records, ClientRecord, OutputFormat};
+    use crate::transaction::tests::{stream, temp_path};
 
     #[tokio::test(flavor = "multi_thread")]
     async fn test_processing_set1() {
@@ -240,14 +811,36 @@ mod tests {
         let transactions = parser.parse_transactions("data/set1.csv").await.unwrap();
 
         let mut engine = TransactionEngine::new().unwrap();
-        engine.process(&transactions);
+        engine.process(transactions).await.unwrap();
         let c1 = engine.clients.get(&1).unwrap();
-        assert_eq!(c1.total, 1.5f32);
-        assert_eq!(c1.available, 1.5f32);
+        assert_eq!(c1.total, "1.5".parse().unwrap());
+        assert_eq!(c1.available, "1.5".parse().unwrap());
+
+        let c2 = engine.clients.get(&2).unwrap();
+        assert_eq!(c2.total, "2.0".parse().unwrap());
+        assert_eq!(c2.available, "2.0".parse().unwrap());
+
+        assert_eq!(engine.rejected.len(), 1);
+        assert_eq!(engine.rejected[0].transaction.tx, 5);
+        assert_eq!(
+            engine.rejected[0].error,
+            TransactionError::InsufficientFunds(2)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_processing_set1_with_overdraft() {
+        let parser = InputParser::new().unwrap();
+        let transactions = parser.parse_transactions("data/set1.csv").await.unwrap();
 
+        let mut policy = Policy::default();
+        policy.overdraft_limits.insert(2, "1".parse().unwrap());
+        let mut engine = TransactionEngine::with_policy(policy).unwrap();
+        engine.process(transactions).await.unwrap();
         let c2 = engine.clients.get(&2).unwrap();
-        assert_eq!(c2.total, -1.0f32);
-        assert_eq!(c2.available, -1.0f32);
+        assert_eq!(c2.total, "-1.0".parse().unwrap());
+        assert_eq!(c2.available, "-1.0".parse().unwrap());
+        assert!(engine.rejected.is_empty());
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -256,16 +849,16 @@ mod tests {
         let transactions = parser.parse_transactions("data/set3.csv").await.unwrap();
 
         let mut engine = TransactionEngine::new().unwrap();
-        engine.process(&transactions);
+        engine.process(transactions).await.unwrap();
         let c1 = engine.clients.get(&1).unwrap();
-        assert_eq!(c1.total, 8f32);
-        assert_eq!(c1.available, 10f32);
-        assert_eq!(c1.held, -2f32);
+        assert_eq!(c1.total, "8".parse().unwrap());
+        assert_eq!(c1.available, "10".parse().unwrap());
+        assert_eq!(c1.held, "-2".parse().unwrap());
 
         let c2 = engine.clients.get(&2).unwrap();
-        assert_eq!(c2.total, 8.0f32);
-        assert_eq!(c2.available, 2.0f32);
-        assert_eq!(c2.held, 6.0f32);
+        assert_eq!(c2.total, "8.0".parse().unwrap());
+        assert_eq!(c2.available, "2.0".parse().unwrap());
+        assert_eq!(c2.held, "6.0".parse().unwrap());
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -274,16 +867,16 @@ mod tests {
         let transactions = parser.parse_transactions("data/set4.csv").await.unwrap();
 
         let mut engine = TransactionEngine::new().unwrap();
-        engine.process(&transactions);
+        engine.process(transactions).await.unwrap();
         let c1 = engine.clients.get(&1).unwrap();
-        assert_eq!(c1.total, 11f32);
-        assert_eq!(c1.available, 11f32);
-        assert_eq!(c1.held, 0f32);
+        assert_eq!(c1.total, "11".parse().unwrap());
+        assert_eq!(c1.available, "11".parse().unwrap());
+        assert_eq!(c1.held, "0".parse().unwrap());
 
         let c2 = engine.clients.get(&2).unwrap();
-        assert_eq!(c2.total, 8.0f32);
-        assert_eq!(c2.available, 8.0f32);
-        assert_eq!(c2.held, 0.0f32);
+        assert_eq!(c2.total, "8.0".parse().unwrap());
+        assert_eq!(c2.available, "8.0".parse().unwrap());
+        assert_eq!(c2.held, "0.0".parse().unwrap());
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -292,16 +885,361 @@ mod tests {
         let transactions = parser.parse_transactions("data/set5.csv").await.unwrap();
 
         let mut engine = TransactionEngine::new().unwrap();
-        engine.process(&transactions);
+        engine.process(transactions).await.unwrap();
+        let c1 = engine.clients.get(&1).unwrap();
+        assert_eq!(c1.total, "0".parse().unwrap());
+        assert_eq!(c1.available, "0".parse().unwrap());
+        assert_eq!(c1.held, "0".parse().unwrap());
+        assert!(c1.locked);
+
+        let c2 = engine.clients.get(&2).unwrap();
+        assert_eq!(c2.total, "8.0".parse().unwrap());
+        assert_eq!(c2.available, "8.0".parse().unwrap());
+        assert_eq!(c2.held, "0.0".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_processing_overflow() {
+        let huge = "900000000000000";
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, huge.parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 2, huge.parse().ok()).unwrap(),
+        ];
+
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.process(stream(transactions)).await.unwrap();
+        assert_eq!(engine.rejected.len(), 1);
+        assert_eq!(engine.rejected[0].transaction.tx, 2);
+        assert_eq!(
+            engine.rejected[0].error,
+            TransactionError::Amount(AmountError::Overflow)
+        );
+        let c1 = engine.clients.get(&1).unwrap();
+        assert_eq!(c1.total, huge.parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_processing_repeated_dispute() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Resolve, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Resolve, 1, 1, None).unwrap(),
+        ];
+
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.process(stream(transactions)).await.unwrap();
         let c1 = engine.clients.get(&1).unwrap();
-        assert_eq!(c1.total, 0f32);
-        assert_eq!(c1.available, 0f32);
-        assert_eq!(c1.held, 0f32);
-        assert_eq!(c1.locked, true);
+        assert_eq!(c1.available, "5".parse().unwrap());
+        assert_eq!(c1.held, Amount::ZERO);
+        assert_eq!(
+            engine.transactions.get(&1).unwrap().state,
+            TransactionState::Resolved
+        );
+
+        let errors: Vec<_> = engine.rejected.iter().map(|r| r.error.clone()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                TransactionError::AlreadyDisputed(1),
+                TransactionError::DisputeSettled(1, TransactionState::Resolved)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_processing_rejections() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Withdrawal, 1, 1, "1".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 2, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 3, None).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 1, 2, None).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 2, None).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 1, 2, None).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 4, "1".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 5, "1".parse().ok()).unwrap(),
+        ];
+
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.process(stream(transactions)).await.unwrap();
+        let rejected: Vec<_> = engine
+            .rejected
+            .iter()
+            .map(|r| (r.transaction.tx, r.error.clone()))
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![
+                (1, TransactionError::UnknownClient(1)),
+                (1, TransactionError::TxRejected(1)),
+                (3, TransactionError::TxNotFound(3)),
+                (2, TransactionError::NotDisputed(2)),
+                (4, TransactionError::AccountLocked(1)),
+                (5, TransactionError::AccountLocked(1)),
+            ]
+        );
+        let codes: Vec<_> = engine.rejected.iter().map(|r| r.error.code()).collect();
+        assert_eq!(
+            codes,
+            vec![
+                "unknown_client",
+                "tx_rejected",
+                "tx_not_found",
+                "not_disputed",
+                "account_locked",
+                "account_locked",
+            ]
+        );
+        let c1 = engine.clients.get(&1).unwrap();
+        assert_eq!(c1.total, Amount::ZERO);
+        assert!(c1.locked);
+    }
+
+    #[tokio::test]
+    async fn test_processing_dispute_states() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 2, "3".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 3, "1".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Resolve, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 2, None).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 1, 2, None).unwrap(),
+            Transaction::new(TransactionType::Resolve, 1, 2, None).unwrap(),
+        ];
+
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.process(stream(transactions)).await.unwrap();
+        let states: Vec<_> = (1..=3)
+            .map(|tx| engine.transactions.get(&tx).unwrap().state)
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                TransactionState::Resolved,
+                TransactionState::ChargedBack,
+                TransactionState::Processed
+            ]
+        );
+
+        let errors: Vec<_> = engine.rejected.iter().map(|r| r.error.clone()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                TransactionError::DisputeSettled(1, TransactionState::Resolved),
+                TransactionError::DisputeSettled(1, TransactionState::Resolved),
+                TransactionError::DisputeSettled(2, TransactionState::ChargedBack),
+            ]
+        );
+        let c1 = engine.clients.get(&1).unwrap();
+        assert_eq!(c1.available, "6".parse().unwrap());
+        assert_eq!(c1.held, Amount::ZERO);
+        assert_eq!(c1.total, "6".parse().unwrap());
+    }
 
+    #[tokio::test]
+    async fn test_processing_foreign_dispute() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 2, 2, "3".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 2, 1, None).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Chargeback, 2, 1, None).unwrap(),
+            Transaction::new(TransactionType::Resolve, 2, 1, None).unwrap(),
+        ];
+
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.process(stream(transactions)).await.unwrap();
+        let errors: Vec<_> = engine.rejected.iter().map(|r| r.error.clone()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                TransactionError::WrongClient(1, 2),
+                TransactionError::WrongClient(1, 2),
+                TransactionError::WrongClient(1, 2),
+            ]
+        );
+        let c1 = engine.clients.get(&1).unwrap();
+        assert_eq!(c1.available, Amount::ZERO);
+        assert_eq!(c1.held, "5".parse().unwrap());
+        assert!(!c1.locked);
         let c2 = engine.clients.get(&2).unwrap();
-        assert_eq!(c2.total, 8.0f32);
-        assert_eq!(c2.available, 8.0f32);
-        assert_eq!(c2.held, 0.0f32);
+        assert_eq!(c2.available, "3".parse().unwrap());
+        assert_eq!(c2.held, Amount::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_processing_duplicates() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 1, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 2, 1, "1".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, "9".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 3, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, "9".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 2, None).unwrap(),
+        ];
+
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.process(stream(transactions)).await.unwrap();
+        let rejected: Vec<_> = engine
+            .rejected
+            .iter()
+            .map(|r| (r.transaction.tx, r.error.clone()))
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![
+                (1, TransactionError::DuplicateTx(1)),
+                (1, TransactionError::DuplicateTx(1)),
+                (2, TransactionError::InsufficientFunds(1)),
+                (2, TransactionError::DuplicateTx(2)),
+                (2, TransactionError::TxRejected(2)),
+            ]
+        );
+        let c1 = engine.clients.get(&1).unwrap();
+        assert_eq!(c1.available, "5".parse().unwrap());
+        assert_eq!(c1.held, "5".parse().unwrap());
+        assert!(!engine.clients.contains_key(&2));
+        assert_eq!(
+            engine.transactions.get(&2).unwrap().state,
+            TransactionState::Rejected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_processing_invalid() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, None).unwrap(),
+            Transaction::new(TransactionType::Deposit, 1, 1, "2".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 1, "2".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, "-2".parse().ok()).unwrap(),
+        ];
+
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.process(stream(transactions)).await.unwrap();
+        let errors: Vec<_> = engine.rejected.iter().map(|r| r.error.clone()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError::MissingAmount(TransactionType::Deposit).into(),
+                ValidationError::UnexpectedAmount(TransactionType::Dispute).into(),
+                ValidationError::NotPositive(TransactionType::Withdrawal).into(),
+            ]
+        );
+        let c1 = engine.clients.get(&1).unwrap();
+        assert_eq!(c1.available, "2".parse().unwrap());
+        assert_eq!(c1.held, Amount::ZERO);
+    }
+
+    #[test]
+    fn test_unchecked_transactions() {
+        // the handlers don't rely on the validation
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 1, None).unwrap();
+        assert_eq!(
+            amount_of(&deposit),
+            Err(ValidationError::MissingAmount(TransactionType::Deposit).into())
+        );
+
+        // e.g. from a hand-edited snapshot
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.clients.insert(1, Client::default());
+        engine.transactions.insert(
+            1,
+            StoredTransaction {
+                client: 1,
+                r#type: TransactionType::Resolve,
+                amount: "1".parse().unwrap(),
+                state: TransactionState::Processed,
+            },
+        );
+        let dispute = Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap();
+        assert_eq!(
+            engine.process_transaction(&dispute),
+            Err(TransactionError::NotDisputable(1, TransactionType::Resolve))
+        );
+        assert_eq!(engine.clients[&1], Client::default());
+    }
+
+    #[test]
+    fn test_builder_apply() {
+        let snapshot = temp_path("builder.json");
+        let log = temp_path("builder.wal");
+        let build = || {
+            TransactionEngine::builder()
+                .snapshot(&snapshot)
+                .wal(&log)
+                .build()
+                .unwrap()
+        };
+
+        let mut engine = TransactionEngine::builder().wal(&log).build().unwrap();
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 1, "5".parse().ok()).unwrap();
+        engine.apply(&deposit).unwrap();
+        engine.save_snapshot(&snapshot).unwrap();
+        let dispute = Transaction::new(TransactionType::Dispute, 1, 1, None).unwrap();
+        engine.apply(&dispute).unwrap();
+        let error = engine.apply(&deposit).unwrap_err();
+        assert!(matches!(
+            error,
+            ApplyError::Rejected(TransactionError::DuplicateTx(1))
+        ));
+        assert_eq!(engine.rejected().len(), 1);
+        engine.sync().unwrap();
+        drop(engine);
+
+        let engine = build();
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.available(), Amount::ZERO);
+        assert_eq!(client.held(), "5".parse().unwrap());
+        assert_eq!(client.total(), "5".parse().unwrap());
+        assert!(!client.locked());
+        assert_eq!(
+            engine.transaction(1).unwrap().state,
+            TransactionState::Disputed
+        );
+        assert!(engine.rejected().is_empty());
+        std::fs::remove_file(&snapshot).unwrap();
+        std::fs::remove_file(&log).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sorted_clients() {
+        let parser = InputParser::new().unwrap();
+        let transactions = parser.parse_transactions("data/set3.csv").await.unwrap();
+
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.process(transactions).await.unwrap();
+        engine.clients.insert(0, Client::default());
+        let ids = |order| -> Vec<u16> {
+            engine
+                .sorted_clients(order)
+                .iter()
+                .map(|(id, _)| *id)
+                .collect()
+        };
+        assert_eq!(ids(ClientOrder::Id), vec![0, 1, 2]);
+        assert_eq!(ids(ClientOrder::Total), vec![1, 2, 0]);
+        assert_eq!(ids(ClientOrder::Available), vec![1, 2, 0]);
+
+        let records: Vec<ClientRecord> = engine
+            .sorted_clients(ClientOrder::Id)
+            .into_iter()
+            .map(|(id, client)| ClientRecord::new(id, client))
+            .collect();
+        let mut output = Vec::new();
+        write_records(&records, OutputFormat::Csv, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             0,0.0000,0.0000,0.0000,false\n\
+             1,10.0000,-2.0000,8.0000,false\n\
+             2,2.0000,6.0000,8.0000,false\n"
+        );
     }
 }
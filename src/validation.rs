@@ -0,0 +1,198 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use thiserror::Error;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::amount::Amount;
+use crate::transaction::{Transaction, TransactionType};
+use crate::transaction_engine::TransactionError;
+
+/// A rule a transaction breaks on its own, regardless of any account.
+#[derive(Debug, Error, PartialEq, Eq, Clone)]
+pub enum ValidationError {
+    /// A deposit or withdrawal without an amount.
+    #[error("a {0} requires an amount")]
+    MissingAmount(TransactionType),
+    /// A deposit or withdrawal of zero or less.
+    #[error("the amount of a {0} must be positive")]
+    NotPositive(TransactionType),
+    /// A dispute step with an amount.
+    #[error("a {0} must not carry an amount")]
+    UnexpectedAmount(TransactionType),
+}
+
+impl ValidationError {
+    /// Stable identifier of the rule that was broken, used in reports.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ValidationError::MissingAmount(_) => "missing_amount",
+            ValidationError::NotPositive(_) => "amount_not_positive",
+            ValidationError::UnexpectedAmount(_) => "unexpected_amount",
+        }
+    }
+}
+
+/// Checks the rules a transaction has to follow independent of any account state.
+/// Whether an amount is a finite number with at most four decimals is already
+/// enforced when it is parsed into an `Amount`.
+pub fn validate(transaction: &Transaction) -> Result<(), ValidationError> {
+    let r#type = &transaction.r#type;
+    match (r#type, transaction.amount) {
+        (TransactionType::Deposit | TransactionType::Withdrawal, None) => {
+            Err(ValidationError::MissingAmount(r#type.clone()))
+        }
+        (TransactionType::Deposit | TransactionType::Withdrawal, Some(amount))
+            if amount <= Amount::ZERO =>
+        {
+            Err(ValidationError::NotPositive(r#type.clone()))
+        }
+        (
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback,
+            Some(_),
+        ) => Err(ValidationError::UnexpectedAmount(r#type.clone())),
+        _ => Ok(()),
+    }
+}
+
+/// Totals of `check_stream`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StreamCheck {
+    /// Transactions checked.
+    pub transactions: u64,
+    /// Transactions that failed a check.
+    pub invalid: u64,
+}
+
+/// Checks every transaction of a stream on its own and deposit and withdrawal ids
+/// for duplicates, without applying anything. `invalid` is called for each
+/// transaction failing a check, errors of the stream itself stop the check.
+pub async fn check_stream(
+    transactions: impl Stream<Item = Result<Transaction>>,
+    mut invalid: impl FnMut(&Transaction, TransactionError),
+) -> Result<StreamCheck> {
+    tokio::pin!(transactions);
+    let mut ids = HashSet::new();
+    let mut check = StreamCheck::default();
+    while let Some(transaction) = transactions.next().await {
+        let transaction = transaction?;
+        check.transactions += 1;
+        let result = validate(&transaction)
+            .map_err(TransactionError::from)
+            .and_then(|()| match transaction.r#type {
+                TransactionType::Deposit | TransactionType::Withdrawal
+                    if !ids.insert(transaction.tx) =>
+                {
+                    Err(TransactionError::DuplicateTx(transaction.tx))
+                }
+                _ => Ok(()),
+            });
+        if let Err(error) = result {
+            invalid(&transaction, error);
+            check.invalid += 1;
+        }
+    }
+    Ok(check)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::tests::stream;
+
+    #[test]
+    fn test_validate_amounts() {
+        let cases = [
+            (TransactionType::Deposit, Some("1.5"), Ok(())),
+            (TransactionType::Withdrawal, Some("0.0001"), Ok(())),
+            (TransactionType::Dispute, None, Ok(())),
+            (TransactionType::Resolve, None, Ok(())),
+            (TransactionType::Chargeback, None, Ok(())),
+            (
+                TransactionType::Deposit,
+                None,
+                Err(ValidationError::MissingAmount(TransactionType::Deposit)),
+            ),
+            (
+                TransactionType::Withdrawal,
+                Some("0"),
+                Err(ValidationError::NotPositive(TransactionType::Withdrawal)),
+            ),
+            (
+                TransactionType::Deposit,
+                Some("-3"),
+                Err(ValidationError::NotPositive(TransactionType::Deposit)),
+            ),
+            (
+                TransactionType::Dispute,
+                Some("1"),
+                Err(ValidationError::UnexpectedAmount(TransactionType::Dispute)),
+            ),
+            (
+                TransactionType::Chargeback,
+                Some("1"),
+                Err(ValidationError::UnexpectedAmount(
+                    TransactionType::Chargeback,
+                )),
+            ),
+        ];
+        for (r#type, amount, expected) in cases {
+            let transaction = Transaction {
+                r#type,
+                client: 1,
+                tx: 1,
+                amount: amount.map(|a| a.parse().unwrap()),
+            };
+            assert_eq!(validate(&transaction), expected);
+        }
+    }
+
+    #[test]
+    fn test_reject_non_finite_amounts() {
+        for amount in ["NaN", "inf", "-inf", "1.23456"] {
+            let input = format!("type,client,tx,amount\ndeposit,1,1,{}", amount);
+            let mut rdr = csv::Reader::from_reader(input.as_bytes());
+            let result: Option<csv::Result<Transaction>> = rdr.deserialize().next();
+            assert!(result.unwrap().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_stream() {
+        let transaction = |r#type, tx, amount: Option<&str>| Transaction {
+            r#type,
+            client: 1,
+            tx,
+            amount: amount.map(|a| a.parse().unwrap()),
+        };
+        let transactions = vec![
+            transaction(TransactionType::Deposit, 1, Some("1")),
+            transaction(TransactionType::Withdrawal, 1, Some("1")),
+            transaction(TransactionType::Dispute, 1, None),
+            transaction(TransactionType::Dispute, 1, None),
+            transaction(TransactionType::Deposit, 2, None),
+            // only valid deposits and withdrawals take their id
+            transaction(TransactionType::Deposit, 2, Some("1")),
+        ];
+        let mut invalid = Vec::new();
+        let check = check_stream(stream(transactions), |t, e| invalid.push((t.tx, e)))
+            .await
+            .unwrap();
+        assert_eq!(
+            check,
+            StreamCheck {
+                transactions: 6,
+                invalid: 2
+            }
+        );
+        assert_eq!(
+            invalid,
+            vec![
+                (1, TransactionError::DuplicateTx(1)),
+                (
+                    2,
+                    ValidationError::MissingAmount(TransactionType::Deposit).into()
+                ),
+            ]
+        );
+    }
+}
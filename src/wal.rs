@@ -0,0 +1,371 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::transaction::Transaction;
+use crate::transaction_engine::{TransactionEngine, TransactionError};
+
+/// Length and checksum of the payload, both little endian u32.
+const HEADER_SIZE: usize = 8;
+/// Records appended between two syncs. A crash loses at most this many of the
+/// last records, although they were already applied.
+pub const SYNC_EVERY: usize = 1000;
+
+/// Append-only log of the transactions the engine accepted, plus the rejected
+/// deposits and withdrawals since they consume their id. Every record is
+/// `length | crc32 | json payload`, so a record cut off by a crash is detected on
+/// recovery. The log is synced every `SYNC_EVERY` records and by `sync`.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    file: BufWriter<File>,
+    /// Records appended since the last sync.
+    unsynced: usize,
+}
+
+/// A logged transaction and how the engine decided on it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    #[serde(flatten)]
+    transaction: Transaction,
+    /// Reason code of a rejection, missing for accepted transactions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rejected: Option<String>,
+}
+
+/// What recovering or replaying a log found.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Recovery {
+    /// Length of the torn record cut off the end of the log, left by a crash while
+    /// appending.
+    pub discarded: usize,
+    /// Records that were accepted or rejected differently than logged, e.g. because
+    /// the snapshot already contains them.
+    pub diverged: usize,
+}
+
+impl WriteAheadLog {
+    pub fn append(
+        &mut self,
+        transaction: &Transaction,
+        rejected: Option<&TransactionError>,
+    ) -> Result<()> {
+        let record = Record {
+            transaction: transaction.clone(),
+            rejected: rejected.map(|error| error.code().to_owned()),
+        };
+        let payload = serde_json::to_vec(&record)?;
+        self.file.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.file
+            .write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        self.file.write_all(&payload)?;
+        self.unsynced += 1;
+        if self.unsynced == SYNC_EVERY {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Makes all appended records durable.
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Writes the buffered records to the file without waiting for the disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Another handle to the file, to sync flushed records without holding on to
+    /// the log.
+    pub fn try_clone_file(&self) -> Result<File> {
+        Ok(self.file.get_ref().try_clone()?)
+    }
+
+    /// Drops all records, called once their effect is saved in a snapshot.
+    pub fn truncate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.file.get_mut().set_len(0)?;
+        self.file.get_mut().seek(SeekFrom::Start(0))?;
+        self.file.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/// Splits the log into its records. Returns the records and the length of the
+/// valid part, an incomplete or corrupt final record is a torn write and left out.
+/// Corruption before the final record can't be explained by a crash and is an error.
+fn read_records(content: &[u8]) -> Result<(Vec<Record>, usize)> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < content.len() {
+        let rest = &content[offset..];
+        if rest.len() < HEADER_SIZE {
+            break;
+        }
+        let length = u32::from_le_bytes(rest[0..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+        let end = HEADER_SIZE + length;
+        if rest.len() < end {
+            break;
+        }
+        let payload = &rest[HEADER_SIZE..end];
+        if crc32fast::hash(payload) != checksum {
+            if rest.len() == end {
+                break;
+            }
+            bail!("Write-ahead log record at offset {} is corrupt", offset);
+        }
+        records.push(serde_json::from_slice(payload)?);
+        offset += end;
+    }
+    Ok((records, offset))
+}
+
+/// Rebuilds the engine by replaying the log on top of its current state, usually
+/// restored from the last snapshot. A torn final record is cut off, the returned
+/// log appends after the last complete record.
+///
+/// Replaying records that are already part of the snapshot is harmless: the
+/// engine refuses known transaction ids and dispute steps that were already taken.
+/// They are counted as diverged though.
+pub fn recover(path: &Path, engine: &mut TransactionEngine) -> Result<(WriteAheadLog, Recovery)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    let (records, valid) = read_records(&content)?;
+    let discarded = content.len() - valid;
+    if discarded > 0 {
+        file.set_len(valid as u64)?;
+        file.sync_data()?;
+    }
+    let diverged = apply_records(&records, engine);
+    file.seek(SeekFrom::Start(valid as u64))?;
+    let wal = WriteAheadLog {
+        file: BufWriter::new(file),
+        unsynced: 0,
+    };
+    Ok((
+        wal,
+        Recovery {
+            discarded,
+            diverged,
+        },
+    ))
+}
+
+/// Replays the log on top of the engine like `recover`, but only reads it. A torn
+/// final record is skipped and left in place.
+pub fn replay(path: &Path, engine: &mut TransactionEngine) -> Result<Recovery> {
+    let content = fs::read(path)
+        .with_context(|| format!("Can't read the write-ahead log {}", path.display()))?;
+    let (records, valid) = read_records(&content)?;
+    Ok(Recovery {
+        discarded: content.len() - valid,
+        diverged: apply_records(&records, engine),
+    })
+}
+
+/// Returns the number of records whose outcome differs from the logged one.
+fn apply_records(records: &[Record], engine: &mut TransactionEngine) -> usize {
+    records
+        .iter()
+        .filter(|record| {
+            let outcome = engine.process_transaction(&record.transaction);
+            outcome.err().map(|error| error.code()) != record.rejected.as_deref()
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::tests::{stream, temp_path};
+    use crate::transaction::TransactionType;
+
+    fn transactions() -> Vec<Transaction> {
+        vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, "5".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, "9".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Deposit, 2, 3, "3".parse().ok()).unwrap(),
+            Transaction::new(TransactionType::Dispute, 2, 3, None).unwrap(),
+        ]
+    }
+
+    async fn write_log(path: &Path) -> TransactionEngine {
+        let _ = fs::remove_file(path);
+        let mut engine = TransactionEngine::new().unwrap();
+        engine.wal = Some(recover(path, &mut engine).unwrap().0);
+        engine.process(stream(transactions())).await.unwrap();
+        engine
+    }
+
+    #[tokio::test]
+    async fn test_recover() {
+        let path = temp_path("recover.wal");
+        let engine = write_log(&path).await;
+        let content = fs::read(&path).unwrap();
+        let (records, valid) = read_records(&content).unwrap();
+        // the rejected withdrawal is logged as it took its id
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[1].rejected.as_deref(), Some("insufficient_funds"));
+        assert_eq!(valid, content.len());
+
+        let mut recovered = TransactionEngine::new().unwrap();
+        let (_, recovery) = recover(&path, &mut recovered).unwrap();
+        assert_eq!(recovery, Recovery::default());
+        assert_eq!(recovered.clients, engine.clients);
+        assert_eq!(recovered.transactions, engine.transactions);
+        assert!(recovered.rejected.is_empty());
+
+        // a rejected id stays taken after recovery
+        let retry = Transaction::new(TransactionType::Deposit, 1, 2, "9".parse().ok()).unwrap();
+        assert_eq!(
+            recovered.process_transaction(&retry),
+            Err(crate::transaction_engine::TransactionError::DuplicateTx(2))
+        );
+
+        // replayed again every record is refused as a duplicate or taken step
+        let recovery = replay(&path, &mut recovered).unwrap();
+        assert_eq!(recovery.diverged, 4);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_record_format() {
+        // logs written before the outcome was recorded can still be read
+        let record: Record =
+            serde_json::from_str(r#"{"type":"deposit","client":1,"tx":2,"amount":"1.5000"}"#)
+                .unwrap();
+        assert_eq!(record.transaction.amount, "1.5".parse().ok());
+        assert_eq!(record.rejected, None);
+        let dispute = Record {
+            transaction: Transaction::new(TransactionType::Dispute, 1, 2, None).unwrap(),
+            rejected: Some("tx_not_found".to_owned()),
+        };
+        assert_eq!(
+            serde_json::to_string(&dispute).unwrap(),
+            r#"{"type":"dispute","client":1,"tx":2,"amount":null,"rejected":"tx_not_found"}"#
+        );
+    }
+
+    #[test]
+    fn test_sync_every() {
+        let path = temp_path("sync.wal");
+        let _ = fs::remove_file(&path);
+        let mut engine = TransactionEngine::new().unwrap();
+        let (mut wal, _) = recover(&path, &mut engine).unwrap();
+        let deposit = transactions().remove(0);
+        for _ in 0..SYNC_EVERY - 1 {
+            wal.append(&deposit, None).unwrap();
+        }
+        assert_eq!(wal.unsynced, SYNC_EVERY - 1);
+        wal.append(&deposit, None).unwrap();
+        assert_eq!(wal.unsynced, 0);
+        let (records, _) = read_records(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(records.len(), SYNC_EVERY);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let path = temp_path("replay.wal");
+        let engine = write_log(&path).await;
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(length - 3)
+            .unwrap();
+
+        let mut replayed = TransactionEngine::new().unwrap();
+        replay(&path, &mut replayed).unwrap();
+        assert_eq!(replayed.clients[&1], engine.clients[&1]);
+        assert_eq!(replayed.transactions.len(), engine.transactions.len());
+        // the torn record is left alone
+        assert_eq!(fs::metadata(&path).unwrap().len(), length - 3);
+        assert!(replay(&temp_path("missing.wal"), &mut replayed).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recover_torn_record() {
+        let path = temp_path("torn.wal");
+        write_log(&path).await;
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(length - 3)
+            .unwrap();
+
+        let mut engine = TransactionEngine::new().unwrap();
+        let (mut wal, recovery) = recover(&path, &mut engine).unwrap();
+        // what is left of the final record, the dispute
+        let last = Record {
+            transaction: transactions()[3].clone(),
+            rejected: None,
+        };
+        let last = serde_json::to_vec(&last).unwrap();
+        assert_eq!(
+            recovery,
+            Recovery {
+                discarded: HEADER_SIZE + last.len() - 3,
+                diverged: 0
+            }
+        );
+        assert_eq!(
+            engine.transactions.get(&3).unwrap().state,
+            crate::transaction_engine::TransactionState::Processed
+        );
+        let resolve = Transaction::new(TransactionType::Resolve, 2, 3, None).unwrap();
+        wal.append(&resolve, None).unwrap();
+        wal.sync().unwrap();
+
+        let (records, valid) = read_records(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(valid as u64, fs::metadata(&path).unwrap().len());
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[3].transaction, resolve);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recover_corrupt_record() {
+        let path = temp_path("corrupt.wal");
+        write_log(&path).await;
+        let mut content = fs::read(&path).unwrap();
+        content[HEADER_SIZE + 2] ^= 0xff;
+        fs::write(&path, content).unwrap();
+
+        let mut engine = TransactionEngine::new().unwrap();
+        let error = recover(&path, &mut engine).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Write-ahead log record at offset 0 is corrupt"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_truncate() {
+        let path = temp_path("truncate.wal");
+        let mut engine = write_log(&path).await;
+        engine.wal.as_mut().unwrap().truncate().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+}